@@ -40,7 +40,7 @@ fn badges<E>(lines: E) -> u32 where E: Iterator<Item = String> {
         .chunks(3)
         .into_iter()
         .map(|chunk| {
-            let mut items = chunk.map(|sack| HashSet::<u8>::from_iter(sack)).collect::<Vec<_>>();
+            let mut items = chunk.map(HashSet::<u8>::from_iter).collect::<Vec<_>>();
             assert_eq!(items.len(), 3);
             let mut badges = items.pop().unwrap();
             // .intersection() will not easily work iteratively for 3 sets:
@@ -1,41 +1,138 @@
+use std::fmt::{Display, Formatter};
 use crate::get_file_lines;
 
 pub fn day04() {
-    let overlap = calc_overlap(get_file_lines("data/04_data.txt"), false);
-    println!("# contain: {overlap}");
-    let overlap = calc_overlap(get_file_lines("data/04_data.txt"), true);
-    println!("# overlap: {overlap}");
+    match calc_overlap(get_file_lines("data/04_data.txt"), false) {
+        Ok(overlap) => println!("# contain: {overlap}"),
+        Err(e) => println!("error: {e}")
+    }
+    match calc_overlap(get_file_lines("data/04_data.txt"), true) {
+        Ok(overlap) => println!("# overlap: {overlap}"),
+        Err(e) => println!("error: {e}")
+    }
+}
+
+/// Inclusive range of section IDs.
+pub type Range = (u32, u32);
+
+/// What to do with a range whose lower bound is greater than its upper bound, like `8-2`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Reversed {
+    /// reports a `ParseErrorKind::Reversed` error
+    Reject,
+    /// swaps the bounds, so `8-2` is read as `2-8`
+    Swap
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ParseErrorKind {
+    /// the line has no range at all
+    Empty,
+    /// a range doesn't have exactly two bounds separated by `-` (the faulty text is given)
+    BadRange(String),
+    /// a bound isn't a valid number (the faulty text is given)
+    BadNumber(String),
+    /// the lower bound is greater than the upper bound
+    Reversed(Range),
+    /// fewer than 2 ranges on the line (the number of ranges found is given)
+    TooFewRanges(usize)
+}
+
+/// Parsing error, with the line number (starting at 1).
+#[derive(Debug, PartialEq)]
+pub struct ParseError {
+    pub line: usize,
+    pub kind: ParseErrorKind
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: ", self.line)?;
+        match &self.kind {
+            ParseErrorKind::Empty => write!(f, "no range"),
+            ParseErrorKind::BadRange(s) => write!(f, "'{s}' is not a range"),
+            ParseErrorKind::BadNumber(s) => write!(f, "'{s}' is not a valid section ID"),
+            ParseErrorKind::Reversed((a, b)) => write!(f, "reversed range {a}-{b}"),
+            ParseErrorKind::TooFewRanges(n) => write!(f, "found {n} range(s), expected at least 2")
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Parses one range like `2-4`. Whitespace is allowed around the numbers.
+fn parse_range(text: &str, reversed: Reversed) -> Result<Range, ParseErrorKind> {
+    let bounds = text.split('-').map(|s| s.trim()).collect::<Vec<_>>();
+    if bounds.len() != 2 {
+        return Err(ParseErrorKind::BadRange(text.trim().to_string()));
+    }
+    let mut id = [0; 2];
+    for (i, b) in bounds.iter().enumerate() {
+        id[i] = b.parse::<u32>().map_err(|_| ParseErrorKind::BadNumber(b.to_string()))?;
+    }
+    match (id[0] <= id[1], reversed) {
+        (true, _) => Ok((id[0], id[1])),
+        (false, Reversed::Swap) => Ok((id[1], id[0])),
+        (false, Reversed::Reject) => Err(ParseErrorKind::Reversed((id[0], id[1])))
+    }
+}
+
+/// Parses a line of comma-separated ranges, like `2-4, 6-8 ,10-12`.
+/// At least two ranges are required.
+pub fn parse_assignment(line: &str, reversed: Reversed) -> Result<Vec<Range>, ParseErrorKind> {
+    if line.trim().is_empty() {
+        return Err(ParseErrorKind::Empty);
+    }
+    let ranges = line.split(',')
+        .map(|text| parse_range(text, reversed))
+        .collect::<Result<Vec<_>, _>>()?;
+    if ranges.len() < 2 {
+        return Err(ParseErrorKind::TooFewRanges(ranges.len()));
+    }
+    Ok(ranges)
+}
+
+/// Parses all the lines, stopping at the first error.
+pub fn parse_assignments<E>(lines: E, reversed: Reversed) -> Result<Vec<Vec<Range>>, ParseError>
+    where E: Iterator<Item = String>
+{
+    lines
+        .enumerate()
+        .map(|(i, line)| parse_assignment(&line, reversed).map_err(|kind| ParseError { line: i + 1, kind }))
+        .collect()
 }
 
 /// range a contains range b
-fn contains(a: (u32, u32), b: (u32, u32)) -> bool {
+fn contains(a: Range, b: Range) -> bool {
     a.0 <= b.0 && b.1 <= a.1
 }
 
 /// ranges a and b overlap
-fn overlaps(a: (u32, u32), b: (u32, u32)) -> bool {
+fn overlaps(a: Range, b: Range) -> bool {
     !(b.1 < a.0 || a.1 < b.0)
 }
 
-/// Counts the number of range couples where
+/// Counts the number of lines where at least one couple of ranges verifies
 /// - partial=false: one range includes another (all IDs of one are included in the other)
 /// - partial=true: ranges overlap (one includes at least an ID of the other)
-fn calc_overlap<E>(lines: E, partial: bool) -> u32 where E: Iterator<Item = String> {
-    lines
-        .map(|line| {
-            let id = line.split(['-', ',']).map(|x| x.parse::<u32>().unwrap()).collect::<Vec<_>>();
-            assert!(id[0] <= id[1]);
-            assert!(id[2] <= id[3]);
-            ((id[0], id[1]), (id[2], id[3]))
-        })
-        .filter(|&(range1, range2)|
-            if partial {
-                overlaps(range1, range2)
-            } else {
-                contains(range1, range2) || contains(range2, range1)
-            }
+///
+/// Reversed ranges are rejected.
+fn calc_overlap<E>(lines: E, partial: bool) -> Result<u32, ParseError> where E: Iterator<Item = String> {
+    let count = parse_assignments(lines, Reversed::Reject)?
+        .into_iter()
+        .filter(|ranges|
+            ranges.iter().enumerate().any(|(i, &range1)|
+                ranges.iter().skip(i + 1).any(|&range2|
+                    if partial {
+                        overlaps(range1, range2)
+                    } else {
+                        contains(range1, range2) || contains(range2, range1)
+                    }
+                )
+            )
         )
-        .count() as u32
+        .count();
+    Ok(count as u32)
 }
 
 #[cfg(test)]
@@ -53,14 +150,40 @@ mod tests {
     fn get_contain() {
         let lines = INPUT.lines().map(|s| s.to_string());
         let contain = calc_overlap(lines, false);
-        assert_eq!(contain, 2);
+        assert_eq!(contain, Ok(2));
     }
 
     #[test]
     fn get_overlap() {
         let lines = INPUT.lines().map(|s| s.to_string());
         let overlap = calc_overlap(lines, true);
-        assert_eq!(overlap, 4);
+        assert_eq!(overlap, Ok(4));
+    }
+
+    #[test]
+    fn parse_tolerant() {
+        assert_eq!(parse_assignment(" 2 - 4 , 6-8 ", Reversed::Reject), Ok(vec![(2, 4), (6, 8)]));
+        assert_eq!(parse_assignment("1-2,3-4,5-6", Reversed::Reject), Ok(vec![(1, 2), (3, 4), (5, 6)]));
+        assert_eq!(parse_assignment("8-2,3-4", Reversed::Swap), Ok(vec![(2, 8), (3, 4)]));
+        assert_eq!(parse_assignment("8-2,3-4", Reversed::Reject), Err(ParseErrorKind::Reversed((8, 2))));
+        assert_eq!(parse_assignment("   ", Reversed::Reject), Err(ParseErrorKind::Empty));
+        assert_eq!(parse_assignment("1-2", Reversed::Reject), Err(ParseErrorKind::TooFewRanges(1)));
+        assert_eq!(parse_assignment("1-2,3", Reversed::Reject), Err(ParseErrorKind::BadRange("3".to_string())));
+        assert_eq!(parse_assignment("1-2,3-x", Reversed::Reject), Err(ParseErrorKind::BadNumber("x".to_string())));
+    }
+
+    #[test]
+    fn parse_errors() {
+        let lines = "1-2,3-4\n5-6,7-".lines().map(|s| s.to_string());
+        let error = parse_assignments(lines, Reversed::Reject).unwrap_err();
+        assert_eq!(error, ParseError { line: 2, kind: ParseErrorKind::BadNumber("".to_string()) });
+        assert_eq!(error.to_string(), "line 2: '' is not a valid section ID");
+    }
+
+    #[test]
+    fn multiple_ranges() {
+        let lines = "1-2,5-6,5-5\n1-2,3-4,5-6".lines().map(|s| s.to_string());
+        assert_eq!(calc_overlap(lines, false), Ok(1));
     }
 
     #[test]
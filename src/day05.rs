@@ -1,4 +1,10 @@
-use std::collections::VecDeque;
+use std::cell::Cell;
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
+use std::str::FromStr;
 use itertools::Itertools;
 use regex::Regex;
 use crate::get_file_lines;
@@ -8,88 +14,843 @@ use crate::get_file_lines;
 enum Verbose { Quiet, Final, All }
 
 pub fn day05() {
-    let crates = top_crates(get_file_lines("data/05_data.txt"), false, Verbose::Final);
-    println!("CrateMover 9000, top crates: {crates}");
-    let crates = top_crates(get_file_lines("data/05_data.txt"), true, Verbose::Final);
-    println!("CrateMover 9001, top crates: {crates}");
+    let cranes: [&dyn Crane; 2] = [&CrateMover9000, &CrateMover9001];
+    for crane in cranes {
+        match top_crates(get_file_lines("data/05_data.txt"), crane, Mode::Strict, Verbose::Final) {
+            Ok(crates) => println!("{}, top crates: {crates}", crane.name()),
+            Err(e) => println!("{}, error: {e}", crane.name())
+        }
+    }
+}
+
+/// Crate label, which is usually a single letter. Labels are shared, so copying a crate is cheap.
+pub type Crate = Rc<str>;
+
+/// Node of a stack, which is an implicit treap: the crates are in the order of an in-order walk
+/// of the tree, balanced by random priorities. The nodes are shared between the clones of a stack
+/// and copied on write, and a subtree is reversed lazily by toggling its `reversed` flag.
+#[derive(Clone, Debug)]
+struct Node {
+    c: Crate,
+    priority: u64,
+    /// number of crates in the subtree
+    len: usize,
+    /// the subtree, children included, must be read in the reverse order
+    reversed: bool,
+    left: Link,
+    right: Link
+}
+
+type Link = Option<Rc<Node>>;
+
+impl Node {
+    fn leaf(c: Crate) -> Link {
+        thread_local! {
+            static SEED: Cell<u64> = const { Cell::new(0) };
+        }
+        // splitmix64, deterministic so that the runs are reproducible
+        let mut z = SEED.with(|seed| {
+            seed.set(seed.get().wrapping_add(0x9e3779b97f4a7c15));
+            seed.get()
+        });
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        Some(Rc::new(Node { c, priority: z ^ (z >> 31), len: 1, reversed: false, left: None, right: None }))
+    }
+
+    /// Applies a pending reversal to the children.
+    fn push_down(&mut self) {
+        if self.reversed {
+            std::mem::swap(&mut self.left, &mut self.right);
+            reverse(&mut self.left);
+            reverse(&mut self.right);
+            self.reversed = false;
+        }
+    }
+
+    fn update(&mut self) {
+        self.len = len(&self.left) + 1 + len(&self.right);
+    }
+}
+
+fn len(link: &Link) -> usize {
+    link.as_ref().map_or(0, |node| node.len)
+}
+
+fn reverse(link: &mut Link) {
+    if let Some(node) = link {
+        let node = Rc::make_mut(node);
+        node.reversed = !node.reversed;
+    }
+}
+
+/// Splits the crates of `link` after the first `n` ones (O(log n)).
+fn split(link: Link, n: usize) -> (Link, Link) {
+    let Some(mut rc) = link else { return (None, None) };
+    let node = Rc::make_mut(&mut rc);
+    node.push_down();
+    let left_len = len(&node.left);
+    if n <= left_len {
+        let (first, second) = split(node.left.take(), n);
+        node.left = second;
+        node.update();
+        (first, Some(rc))
+    } else {
+        let (first, second) = split(node.right.take(), n - left_len - 1);
+        node.right = first;
+        node.update();
+        (Some(rc), second)
+    }
+}
+
+/// Concatenates the crates of `first` and `second` (O(log n)).
+fn merge(first: Link, second: Link) -> Link {
+    match (first, second) {
+        (None, link) | (link, None) => link,
+        (Some(mut a), Some(mut b)) => {
+            if a.priority > b.priority {
+                let node = Rc::make_mut(&mut a);
+                node.push_down();
+                node.right = merge(node.right.take(), Some(b));
+                node.update();
+                Some(a)
+            } else {
+                let node = Rc::make_mut(&mut b);
+                node.push_down();
+                node.left = merge(Some(a), node.left.take());
+                node.update();
+                Some(b)
+            }
+        }
+    }
+}
+
+/// In-order walk of a stack, from the bottom to the top.
+struct Iter<'a> {
+    /// nodes still to visit, with the pending reversal of their subtree
+    path: Vec<(&'a Node, bool)>
+}
+
+impl<'a> Iter<'a> {
+    fn descend(&mut self, mut link: &'a Link, mut reversed: bool) {
+        while let Some(node) = link {
+            reversed ^= node.reversed;
+            self.path.push((node, reversed));
+            link = if reversed { &node.right } else { &node.left };
+        }
+    }
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = &'a Crate;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (node, reversed) = self.path.pop()?;
+        self.descend(if reversed { &node.left } else { &node.right }, reversed);
+        Some(&node.c)
+    }
+}
+
+/// Stack of crates, from the bottom to the top. Moving or reversing a block of crates takes
+/// O(log n), and cloning a stack is O(1).
+#[derive(Clone, Debug, Default)]
+struct Stack {
+    root: Link
+}
+
+impl Stack {
+    fn from_crates(crates: Vec<Crate>) -> Self {
+        Stack { root: crates.into_iter().fold(None, |root, c| merge(root, Node::leaf(c))) }
+    }
+
+    fn len(&self) -> usize {
+        len(&self.root)
+    }
+
+    /// Crates from the bottom to the top.
+    fn iter(&self) -> Iter<'_> {
+        let mut iter = Iter { path: Vec::new() };
+        iter.descend(&self.root, false);
+        iter
+    }
+
+    /// Crate at `index`, from the bottom.
+    fn get(&self, mut index: usize) -> Option<&Crate> {
+        let mut link = &self.root;
+        let mut reversed = false;
+        while let Some(node) = link {
+            reversed ^= node.reversed;
+            let (first, second) = if reversed { (&node.right, &node.left) } else { (&node.left, &node.right) };
+            match index.cmp(&len(first)) {
+                Ordering::Less => link = first,
+                Ordering::Equal => return Some(&node.c),
+                Ordering::Greater => {
+                    index -= len(first) + 1;
+                    link = second;
+                }
+            }
+        }
+        None
+    }
+
+    fn push(&mut self, c: Crate) {
+        self.root = merge(self.root.take(), Node::leaf(c));
+    }
+
+    fn pop(&mut self) -> Option<Crate> {
+        let top = self.split_top(1);
+        top.root.map(|node| node.c.clone())
+    }
+
+    /// Removes the `n` crates on top, and returns them as a stack.
+    fn split_top(&mut self, n: usize) -> Stack {
+        let len = self.len();
+        let (rest, top) = split(self.root.take(), len - n.min(len));
+        self.root = rest;
+        Stack { root: top }
+    }
+
+    /// Puts a block of crates on top of the stack, reversing their order if `reverse` is true.
+    fn append(&mut self, mut block: Stack, reverse: bool) {
+        if reverse {
+            self::reverse(&mut block.root);
+        }
+        self.root = merge(self.root.take(), block.root);
+    }
+}
+
+impl PartialEq for Stack {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.iter().eq(other.iter())
+    }
+}
+
+impl Eq for Stack {}
+
+impl Hash for Stack {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.len().hash(state);
+        for c in self.iter() {
+            c.hash(state);
+        }
+    }
+}
+
+/// Stacks of crates. Each stack is stored from the bottom to the top.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Stacks {
+    stacks: Vec<Stack>
+}
+
+impl Stacks {
+    /// Creates `n` empty stacks.
+    pub fn new(n: usize) -> Self {
+        Stacks { stacks: vec![Stack::default(); n] }
+    }
+
+    /// Parses the initial drawing of the stacks, up to and including the line with the stack numbers.
+    ///
+    /// The crate labels can have any width, like `[AB12]`. Each crate belongs to the stack whose
+    /// number is the closest to the crate horizontally.
+    pub fn parse<E>(lines: &mut E) -> Self where E: Iterator<Item = String> {
+        let re_crates = Regex::new("\\[([^\\]]*)\\]").unwrap();
+        let re_nums = Regex::new("\\d+").unwrap();
+
+        // (horizontal position, label) of the crates, from the top to the bottom
+        let mut rows = Vec::new();
+        let mut centers = Vec::new();
+        for line in lines.by_ref() {
+            if !line.contains('[') {
+                // stack numbers, gives the position of each stack
+                centers = re_nums.find_iter(&line).map(|m| m.start() + m.end()).collect::<Vec<_>>();
+                break;
+            }
+            rows.push(re_crates.captures_iter(&line)
+                .map(|m| {
+                    let (position, label) = (m.get(0).unwrap(), m.get(1).unwrap());
+                    (position.start() + position.end(), Crate::from(label.as_str()))
+                })
+                .collect::<Vec<_>>()
+            );
+        }
+        let mut stacks: Vec<Vec<Crate>> = vec![Vec::new(); centers.len()];
+        for (center, label) in rows.into_iter().rev().flatten() {
+            let index = match centers.iter().position_min_by_key(|&&c| c.abs_diff(center)) {
+                Some(index) => index,
+                // no stack numbers, assumes the standard 4-column layout
+                None => center / 8
+            };
+            if index >= stacks.len() {
+                stacks.resize(index + 1, Vec::new());
+            }
+            stacks[index].push(label);
+        }
+        Stacks { stacks: stacks.into_iter().map(Stack::from_crates).collect() }
+    }
+
+    /// Number of stacks.
+    pub fn len(&self) -> usize {
+        self.stacks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stacks.is_empty()
+    }
+
+    /// Number of crates in stack `index`.
+    pub fn height(&self, index: usize) -> usize {
+        self.stacks[index].len()
+    }
+
+    /// Crates of stack `index`, from the bottom to the top.
+    pub fn crates(&self, index: usize) -> Vec<Crate> {
+        self.stacks[index].iter().cloned().collect()
+    }
+
+    /// Puts crate `c` on top of stack `index`.
+    pub fn push(&mut self, index: usize, c: Crate) {
+        self.stacks[index].push(c);
+    }
+
+    /// Removes the crate on top of stack `index`.
+    pub fn pop(&mut self, index: usize) -> Option<Crate> {
+        self.stacks[index].pop()
+    }
+
+    /// Moves the `num` crates on top of stack `from` to the top of stack `to`, as a single block.
+    /// The crates keep their order if `reverse` is false, or are put in the reverse order if it's true.
+    pub fn move_block(&mut self, from: usize, to: usize, num: usize, reverse: bool) {
+        let block = self.stacks[from].split_top(num);
+        self.stacks[to].append(block, reverse);
+    }
+
+    /// Removes the `num` crates on top of stack `index`.
+    pub fn remove(&mut self, index: usize, num: usize) {
+        self.stacks[index].split_top(num);
+    }
+
+    /// Puts `crates` on top of stack `index`, the first one at the bottom.
+    pub fn extend(&mut self, index: usize, crates: &[Crate]) {
+        self.stacks[index].append(Stack::from_crates(crates.to_vec()), false);
+    }
+
+    /// The `num` crates on top of stack `index`, from the bottom to the top.
+    pub fn peek(&self, index: usize, num: usize) -> Vec<Crate> {
+        let stack = &self.stacks[index];
+        (stack.len() - num.min(stack.len())..stack.len()).filter_map(|i| stack.get(i)).cloned().collect()
+    }
+
+    /// Checks that `mv` can be applied to the stacks.
+    pub fn check(&self, mv: &Move) -> Result<(), MoveErrorKind> {
+        for stack in [mv.from, mv.to] {
+            if stack >= self.stacks.len() {
+                return Err(MoveErrorKind::NoStack { stack, count: self.stacks.len() });
+            }
+        }
+        if self.stacks[mv.from].len() < mv.num {
+            return Err(MoveErrorKind::NotEnoughCrates { num: mv.num, stack: mv.from, crates: self.crates(mv.from) });
+        }
+        Ok(())
+    }
+
+    /// Labels of the crates on top of each stack, or ' ' for empty stacks.
+    pub fn top(&self) -> String {
+        self.stacks.iter().map(|s| s.len().checked_sub(1).and_then(|i| s.get(i)).map_or(" ", |c| c)).join("")
+    }
+}
+
+impl Display for Stacks {
+    /// Draws the stacks in the puzzle's format, which can be read back by `Stacks::parse`:
+    /// ```text
+    ///     [D]
+    /// [N] [C]
+    /// [Z] [M] [P]
+    ///  1   2   3
+    /// ```
+    /// All the columns have the width of the widest crate or stack number.
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let stacks = (0..self.len()).map(|i| self.crates(i)).collect::<Vec<_>>();
+        let height = stacks.iter().map(|s| s.len()).max().unwrap_or(0);
+        let width = stacks.iter().flatten().map(|c| c.len() + 2)
+            .chain([3, self.len().to_string().len()])
+            .max().unwrap();
+        for level in (0..height).rev() {
+            let row = stacks.iter()
+                .map(|s| format!("{:^width$}", s.get(level).map(|c| format!("[{c}]")).unwrap_or_default()))
+                .join(" ");
+            writeln!(f, "{}", row.trim_end())?;
+        }
+        let numbers = (1..=self.stacks.len()).map(|n| format!("{n:^width$}")).join(" ");
+        write!(f, "{}", numbers.trim_end())
+    }
+}
+
+/// Crane move. The stack indices start at 0, unlike the puzzle's notation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Move {
+    pub num: usize,
+    pub from: usize,
+    pub to: usize
+}
+
+impl Display for Move {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "move {} from {} to {}", self.num, self.from + 1, self.to + 1)
+    }
+}
+
+impl FromStr for Move {
+    type Err = String;
+
+    /// Parses a move in the puzzle's notation, like `move 1 from 2 to 1`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let words = s.split_whitespace().collect::<Vec<_>>();
+        if words.len() != 6 || words[0] != "move" || words[2] != "from" || words[4] != "to" {
+            return Err(format!("'{}' is not a move", s.trim()));
+        }
+        let num = |w: &str| w.parse::<usize>().map_err(|_| format!("'{w}' is not a number"));
+        let stack = |w: &str| num(w)?.checked_sub(1).ok_or_else(|| "stacks are numbered from 1".to_string());
+        Ok(Move { num: num(words[1])?, from: stack(words[3])?, to: stack(words[5])? })
+    }
+}
+
+/// Reason why a move can't be applied.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MoveErrorKind {
+    /// the line isn't a valid move
+    Syntax(String),
+    /// `stack` doesn't exist, there are only `count` stacks
+    NoStack { stack: usize, count: usize },
+    /// `stack` doesn't have the `num` crates to move, it only has `crates`
+    NotEnoughCrates { num: usize, stack: usize, crates: Vec<Crate> }
+}
+
+/// Invalid move, with the line number (starting at 1) and its text.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MoveError {
+    pub line: usize,
+    pub text: String,
+    pub kind: MoveErrorKind
+}
+
+impl Display for MoveError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {} '{}': ", self.line, self.text.trim())?;
+        match &self.kind {
+            MoveErrorKind::Syntax(msg) => write!(f, "{msg}"),
+            MoveErrorKind::NoStack { stack, count } =>
+                write!(f, "stack {} doesn't exist, there are {count} stacks", stack + 1),
+            MoveErrorKind::NotEnoughCrates { num, stack, crates } if crates.is_empty() =>
+                write!(f, "cannot take {num} crate(s) from stack {}, which is empty", stack + 1),
+            MoveErrorKind::NotEnoughCrates { num, stack, crates } =>
+                write!(f, "cannot take {num} crate(s) from stack {}, which only contains {}",
+                       stack + 1, crates.iter().map(|c| format!("[{c}]")).join(" "))
+        }
+    }
+}
+
+impl std::error::Error for MoveError {}
+
+/// How the simulation handles invalid moves.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    /// stops at the first invalid move
+    Strict,
+    /// skips the invalid moves and carries on
+    BestEffort
+}
+
+/// Result of a simulation: the final stacks and the moves which were skipped in best-effort mode.
+#[derive(Clone, Debug)]
+pub struct Simulation {
+    pub stacks: Stacks,
+    pub skipped: Vec<MoveError>
+}
+
+/// Crane model, which defines how crates are moved from stack to stack.
+pub trait Crane {
+    /// Model name.
+    fn name(&self) -> String;
+
+    /// Moves `mv.num` crates from `mv.from` to `mv.to`. `index` is the position of the move in the
+    /// sequence, starting at 0.
+    fn move_crates(&self, stacks: &mut Stacks, mv: &Move, index: usize);
+}
+
+/// Picks the crates one at a time, so a moved chunk ends up in the reverse order.
+pub struct CrateMover9000;
+
+impl Crane for CrateMover9000 {
+    fn name(&self) -> String {
+        "CrateMover 9000".to_string()
+    }
+
+    fn move_crates(&self, stacks: &mut Stacks, mv: &Move, _index: usize) {
+        stacks.move_block(mv.from, mv.to, mv.num, true);
+    }
+}
+
+/// Picks all the crates at once, so a moved chunk keeps its order.
+pub struct CrateMover9001;
+
+impl Crane for CrateMover9001 {
+    fn name(&self) -> String {
+        "CrateMover 9001".to_string()
+    }
+
+    fn move_crates(&self, stacks: &mut Stacks, mv: &Move, _index: usize) {
+        stacks.move_block(mv.from, mv.to, mv.num, false);
+    }
+}
+
+/// Picks at most `capacity` crates at a time, each chunk keeping its order.
+pub struct LimitedCrane {
+    pub capacity: usize
+}
+
+impl Crane for LimitedCrane {
+    fn name(&self) -> String {
+        format!("Limited crane ({})", self.capacity)
+    }
+
+    fn move_crates(&self, stacks: &mut Stacks, mv: &Move, _index: usize) {
+        assert!(self.capacity > 0);
+        let mut left = mv.num;
+        while left > 0 {
+            let n = left.min(self.capacity);
+            stacks.move_block(mv.from, mv.to, n, false);
+            left -= n;
+        }
+    }
+}
+
+/// Picks all the crates at once, but reverses the chunk every other move (starting with the 2nd).
+pub struct AlternatingCrane;
+
+impl Crane for AlternatingCrane {
+    fn name(&self) -> String {
+        "Alternating crane".to_string()
+    }
+
+    fn move_crates(&self, stacks: &mut Stacks, mv: &Move, index: usize) {
+        stacks.move_block(mv.from, mv.to, mv.num, index % 2 == 1);
+    }
+}
+
+/// Changes made by one move: the crates removed from the top of `mv.from`, and the crates
+/// then added on top of `mv.to`, both from the bottom to the top.
+#[derive(Clone, Debug)]
+struct Step {
+    mv: Move,
+    removed: Vec<Crate>,
+    added: Vec<Crate>
+}
+
+/// Difference in one stack between two steps of a replay.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StackDiff {
+    pub index: usize,
+    pub before: Vec<Crate>,
+    pub after: Vec<Crate>
+}
+
+/// Recorded sequence of moves, which can be stepped forward and backward.
+///
+/// The position is the number of moves applied to the initial stacks, from 0 to `len()`.
+pub struct Replay {
+    stacks: Stacks,
+    steps: Vec<Step>,
+    position: usize
+}
+
+impl Replay {
+    /// Records the `crane` applying `moves` to `stacks`. The replay starts at position 0.
+    ///
+    /// The moves must be valid (see `Stacks::check`).
+    pub fn record<M>(stacks: Stacks, moves: M, crane: &dyn Crane) -> Self
+        where M: Iterator<Item = Move>
+    {
+        let mut current = stacks.clone();
+        let mut steps = Vec::new();
+        for (index, mv) in moves.enumerate() {
+            let removed = current.peek(mv.from, mv.num);
+            crane.move_crates(&mut current, &mv, index);
+            let added = current.peek(mv.to, mv.num);
+            steps.push(Step { mv, removed, added });
+        }
+        Replay { stacks, steps, position: 0 }
+    }
+
+    /// Reads the puzzle's input (the drawing of the stacks, an empty line, then the moves) and
+    /// records the `crane` applying the moves, which are checked first. Blank lines are ignored.
+    pub fn parse<E>(mut lines: E, crane: &dyn Crane) -> Result<Self, MoveError>
+        where E: Iterator<Item = String>
+    {
+        let mut drawing_lines = 0;
+        let stacks = Stacks::parse(&mut lines.by_ref().inspect(|_| drawing_lines += 1));
+        lines.next();
+        let mut current = stacks.clone();
+        let mut moves = Vec::new();
+        for (i, text) in lines.enumerate().filter(|(_, text)| !text.trim().is_empty()) {
+            let mv = text.parse::<Move>()
+                .map_err(MoveErrorKind::Syntax)
+                .and_then(|mv| current.check(&mv).map(|_| mv))
+                .map_err(|kind| MoveError { line: drawing_lines + i + 2, text, kind })?;
+            crane.move_crates(&mut current, &mv, moves.len());
+            moves.push(mv);
+        }
+        Ok(Replay::record(stacks, moves.into_iter(), crane))
+    }
+
+    /// Number of recorded moves.
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// Number of moves applied so far.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Stacks at the current position.
+    pub fn stacks(&self) -> &Stacks {
+        &self.stacks
+    }
+
+    /// Move number `index`, starting at 0.
+    pub fn get_move(&self, index: usize) -> Option<&Move> {
+        self.steps.get(index).map(|step| &step.mv)
+    }
+
+    /// Applies the next move. Returns false if there are no more moves.
+    pub fn forward(&mut self) -> bool {
+        match self.steps.get(self.position) {
+            Some(step) => {
+                self.stacks.remove(step.mv.from, step.removed.len());
+                self.stacks.extend(step.mv.to, &step.added);
+                self.position += 1;
+                true
+            }
+            None => false
+        }
+    }
+
+    /// Undoes the previous move. Returns false if the replay is at the initial position.
+    pub fn backward(&mut self) -> bool {
+        if self.position == 0 {
+            return false;
+        }
+        self.position -= 1;
+        let step = &self.steps[self.position];
+        self.stacks.remove(step.mv.to, step.added.len());
+        self.stacks.extend(step.mv.from, &step.removed);
+        true
+    }
+
+    /// Moves forward or backward to `position`, which is capped to `len()`.
+    pub fn goto(&mut self, position: usize) {
+        let position = position.min(self.len());
+        while self.position < position {
+            self.forward();
+        }
+        while self.position > position {
+            self.backward();
+        }
+    }
+
+    /// Stacks after `position` moves.
+    pub fn stacks_at(&mut self, position: usize) -> &Stacks {
+        self.goto(position);
+        &self.stacks
+    }
+
+    /// Crates of stack `index` after `position` moves, from the bottom to the top.
+    pub fn stack_at(&mut self, position: usize, index: usize) -> Vec<Crate> {
+        self.stacks_at(position).crates(index)
+    }
+
+    /// Stacks which differ between positions `a` and `b`. The current position is preserved.
+    pub fn diff(&mut self, a: usize, b: usize) -> Vec<StackDiff> {
+        let position = self.position;
+        let before = self.stacks_at(a).clone();
+        let after = self.stacks_at(b);
+        let diff = (0..after.len())
+            .map(|index| StackDiff { index, before: before.crates(index), after: after.crates(index) })
+            .filter(|d| d.before != d.after)
+            .collect();
+        self.goto(position);
+        diff
+    }
 }
 
 /// Displays the stacks.
-fn show_stacks(text: &str, stacks: &Vec<VecDeque<char>>) {
+fn show_stacks(text: &str, stacks: &Stacks) {
     println!("{text}");
-    for (i, s) in stacks.iter().enumerate() {
+    for (i, s) in stacks.stacks.iter().enumerate() {
         println!("- {:2}: {}", i + 1, s.iter()
-            .map(|&c| if c == ' ' { "   ".to_string() } else { format!("[{c}]") })
+            .map(|c| format!("[{c}]"))
             .join(" ")
         )
     }
 }
 
-/// Simulates a crane moving crates from stack to stack.
-/// The initial position of the crates and the moves are described in `lines`.
-/// - multi: true if the crane moves several crates at the same time, false if it picks them one at a time
+/// Simulates the `crane` applying the moves to `stacks`, and returns the final stacks.
+/// - moves: lines of moves with their line number, blank lines are ignored
+/// - mode: what to do with invalid moves
 /// - verbose: level of verbosity
-fn top_crates<E>(mut lines: E, multi: bool, verbose: Verbose) -> String where E: Iterator<Item = String> {
-    // extract the initial content of the stacks
-    let mut stacks: Vec<VecDeque<char>> = Vec::new();
-    let re_crates = Regex::new("(?:.(.).) ?").unwrap();
-    let re_nums = Regex::new("\\d+").unwrap();
-
-    while let Some(line) = lines.next() {
-        if !line.contains('[') {
-            // stack numbers, check if there are any empty stacks to add
-            let numbers = re_nums.find_iter(&line).map(|m| m.as_str()).count();
-            for _ in stacks.len()..numbers {
-                stacks.push(VecDeque::new());
+fn simulate<M>(mut stacks: Stacks, moves: M, crane: &dyn Crane, mode: Mode, verbose: Verbose) -> Result<Simulation, MoveError>
+    where M: Iterator<Item = (usize, String)>
+{
+    if verbose == Verbose::All {
+        show_stacks("Initial:", &stacks);
+    }
+    let mut skipped = Vec::new();
+    let mut index = 0;
+    for (line, text) in moves.filter(|(_, text)| !text.trim().is_empty()) {
+        let checked = text.parse::<Move>()
+            .map_err(MoveErrorKind::Syntax)
+            .and_then(|mv| stacks.check(&mv).map(|_| mv));
+        match checked {
+            Ok(mv) => {
+                crane.move_crates(&mut stacks, &mv, index);
+                index += 1;
+                if verbose == Verbose::All {
+                    show_stacks(&format!("{}: ", text.trim_end()), &stacks);
+                }
+            }
+            Err(kind) => {
+                let error = MoveError { line, text, kind };
+                if mode == Mode::Strict {
+                    return Err(error);
+                }
+                if verbose == Verbose::All {
+                    println!("skipped {error}");
+                }
+                skipped.push(error);
             }
-            break;
-        }
-        // captures the items, ' ' is nothing, otherwise it's a crate
-        let crates = re_crates.captures_iter(&line)
-            .map(|m| m.get(1).unwrap().as_str().chars().next().unwrap())
-            .collect::<Vec<_>>();
-        for _ in stacks.len()..crates.len() {
-            stacks.push(VecDeque::new());
-        }
-        for (s, c) in stacks.iter_mut().zip(crates).filter(|(_, c)| *c != ' ') {
-            s.push_front(c);
         }
     }
-    if verbose == Verbose::All {
-        show_stacks("Initial:", &stacks);
+    if verbose == Verbose::Final {
+        show_stacks("Final:", &stacks);
     }
+    Ok(Simulation { stacks, skipped })
+}
 
-    // empty line
-    lines.next();
+/// Reason why `solve` couldn't find a sequence of moves.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SolveError {
+    /// the target doesn't have the same stacks and crates as the initial stacks
+    Incompatible,
+    /// no solution found after exploring the maximum number of states
+    TooManyStates(usize),
+    /// the target can't be reached: all the reachable states (their number is given) were explored
+    Unreachable(usize)
+}
 
-    // move crates
-    while let Some(line) = lines.next() {
-        let numbers = re_nums.find_iter(&line).map(|m| m.as_str().parse::<usize>().unwrap()).collect::<Vec<_>>();
-        let (num, from, to) = (numbers[0], numbers[1] - 1, numbers[2] - 1);
-        let mut chunk = VecDeque::<char>::new();
-        for _ in 0..num {
-            let c = stacks[from].pop_back().unwrap();
-            if multi {
-                chunk.push_front(c);
-            } else {
-                chunk.push_back(c);
-            }
+impl Display for SolveError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SolveError::Incompatible => write!(f, "the target doesn't have the same stacks and crates"),
+            SolveError::TooManyStates(n) => write!(f, "no solution found in {n} states"),
+            SolveError::Unreachable(n) => write!(f, "target unreachable, all {n} reachable states explored")
         }
-        stacks[to].append(&mut chunk);
-        if verbose == Verbose::All {
-            show_stacks(&format!("{}: ", line.trim_end()), &stacks);
+    }
+}
+
+impl std::error::Error for SolveError {}
+
+/// Searches a shortest sequence of moves which allows the `crane` to rearrange `initial` into `target`,
+/// exploring at most `max_states` different arrangements (breadth-first search).
+///
+/// The solution is the shortest one for cranes which don't depend on the move index. For the other
+/// cranes, like `AlternatingCrane`, arrangements already reached at a different index aren't explored
+/// again, so the solution may be a little longer than the optimum.
+pub fn solve(initial: &Stacks, target: &Stacks, crane: &dyn Crane, max_states: usize) -> Result<Vec<Move>, SolveError> {
+    let sorted_crates = |stacks: &Stacks| stacks.stacks.iter().flat_map(|s| s.iter()).cloned().sorted().collect::<Vec<_>>();
+    if initial.len() != target.len() || sorted_crates(initial) != sorted_crates(target) {
+        return Err(SolveError::Incompatible);
+    }
+    // each state is stored with its parent state and the move from the parent
+    let mut states: Vec<(Stacks, usize, Option<Move>)> = vec![(initial.clone(), 0, None)];
+    let mut visited = HashSet::<Stacks>::new();
+    visited.insert(initial.clone());
+    let mut depths = vec![0];
+    let mut next = 0;
+    let mut found = (initial == target).then_some(0);
+    while found.is_none() && next < states.len() {
+        let (current, depth) = (states[next].0.clone(), depths[next]);
+        'moves: for from in 0..current.len() {
+            for to in (0..current.len()).filter(|&to| to != from) {
+                for num in 1..=current.height(from) {
+                    let mv = Move { num, from, to };
+                    let mut stacks = current.clone();
+                    crane.move_crates(&mut stacks, &mv, depth);
+                    if visited.insert(stacks.clone()) {
+                        let is_target = &stacks == target;
+                        states.push((stacks, next, Some(mv)));
+                        depths.push(depth + 1);
+                        if is_target {
+                            found = Some(states.len() - 1);
+                            break 'moves;
+                        }
+                        if states.len() >= max_states {
+                            return Err(SolveError::TooManyStates(max_states));
+                        }
+                    }
+                }
+            }
         }
+        next += 1;
     }
-    if verbose == Verbose::Final {
-        show_stacks("Final:", &stacks);
+    let mut index = found.ok_or(SolveError::Unreachable(states.len()))?;
+    let mut moves = Vec::new();
+    while let (_, parent, Some(mv)) = &states[index] {
+        moves.push(*mv);
+        index = *parent;
     }
+    moves.reverse();
+    Ok(moves)
+}
 
-    stacks.iter().map(|s| s.iter().last().unwrap_or(&' ').to_owned()).join("")
+/// Writes the stacks and the moves in the puzzle's format.
+pub fn write_puzzle(stacks: &Stacks, moves: &[Move]) -> String {
+    format!("{stacks}\n\n{}", moves.iter().join("\n"))
+}
+
+/// Simulates a crane moving crates from stack to stack, and returns the crates on top of the stacks.
+/// The initial position of the crates and the moves are described in `lines`.
+/// - crane: crane model
+/// - mode: what to do with invalid moves
+/// - verbose: level of verbosity
+fn top_crates<E>(mut lines: E, crane: &dyn Crane, mode: Mode, verbose: Verbose) -> Result<String, MoveError>
+    where E: Iterator<Item = String>
+{
+    // extract the initial content of the stacks
+    let mut drawing_lines = 0;
+    let stacks = Stacks::parse(&mut lines.by_ref().inspect(|_| drawing_lines += 1));
+
+    // empty line
+    lines.next();
+
+    // move crates
+    let moves = lines.enumerate().map(|(i, line)| (drawing_lines + i + 2, line));
+    Ok(simulate(stacks, moves, crane, mode, verbose)?.stacks.top())
 }
 
 #[cfg(test)]
 mod tests {
+    use std::collections::VecDeque;
+    use std::time::Instant;
     use super::*;
+
+    fn labels(crates: &str) -> Vec<Crate> {
+        crates.chars().map(|c| Crate::from(c.to_string())).collect()
+    }
+
     const INPUT: &str =
 "    [D]
 [N] [C]
@@ -104,15 +865,267 @@ move 1 from 1 to 2";
     #[test]
     fn get_top_crates_9000() {
         let lines = INPUT.lines().map(|s| s.to_string());
-        let crates = top_crates(lines, false, Verbose::All);
-        assert_eq!(crates, "CMZ");
+        let crates = top_crates(lines, &CrateMover9000, Mode::Strict, Verbose::All);
+        assert_eq!(crates, Ok("CMZ".to_string()));
     }
 
     #[test]
     fn get_top_crates_9001() {
         let lines = INPUT.lines().map(|s| s.to_string());
-        let crates = top_crates(lines, true, Verbose::All);
-        assert_eq!(crates, "MCD");
+        let crates = top_crates(lines, &CrateMover9001, Mode::Strict, Verbose::All);
+        assert_eq!(crates, Ok("MCD".to_string()));
+    }
+
+    #[test]
+    fn other_cranes() {
+        let mut lines = INPUT.lines().map(|s| s.to_string());
+        let stacks = Stacks::parse(&mut lines);
+        let moves = [(1, "move 3 from 2 to 3".to_string()), (2, "move 3 from 3 to 1".to_string())];
+        let result = simulate(stacks.clone(), moves.clone().into_iter(), &LimitedCrane { capacity: 2 }, Mode::Strict, Verbose::Quiet);
+        let result = result.unwrap().stacks;
+        assert_eq!(result.crates(0), labels("ZNDMC"));
+        assert_eq!(result.crates(2), labels("P"));
+        let result = simulate(stacks, moves.into_iter(), &AlternatingCrane, Mode::Strict, Verbose::Quiet);
+        assert_eq!(result.unwrap().stacks.crates(0), labels("ZNDCM"));
+    }
+
+    #[test]
+    fn parse_move() {
+        assert_eq!("move 3 from 1 to 12".parse::<Move>(), Ok(Move { num: 3, from: 0, to: 11 }));
+        assert_eq!(Move { num: 3, from: 0, to: 11 }.to_string(), "move 3 from 1 to 12");
+        assert!("move 3 from 0 to 1".parse::<Move>().is_err());
+        assert!("move 3 to 1".parse::<Move>().is_err());
+    }
+
+    #[test]
+    fn replay() {
+        let mut lines = INPUT.lines().map(|s| s.to_string());
+        let stacks = Stacks::parse(&mut lines);
+        lines.next();
+        let moves = lines.map(|line| line.parse::<Move>().unwrap());
+        let mut replay = Replay::record(stacks.clone(), moves, &CrateMover9000);
+        assert_eq!(replay.len(), 4);
+        assert_eq!(replay.get_move(1), Some(&Move { num: 3, from: 0, to: 2 }));
+        assert_eq!(replay.stack_at(2, 2), labels("PDNZ"));
+        assert_eq!(replay.position(), 2);
+        while replay.forward() {}
+        assert_eq!(replay.stacks().top(), "CMZ");
+        assert_eq!(replay.diff(3, 4), vec![
+            StackDiff { index: 0, before: labels("CM"), after: labels("C") },
+            StackDiff { index: 1, before: labels(""), after: labels("M") },
+        ]);
+        assert_eq!(replay.position(), 4);
+        while replay.backward() {}
+        assert_eq!(replay.stacks(), &stacks);
+
+        let mut parsed = Replay::parse(INPUT.lines().map(|s| s.to_string()), &CrateMover9000).unwrap();
+        assert_eq!(parsed.len(), 4);
+        assert_eq!(parsed.stacks_at(4).top(), "CMZ");
+        let lines = INPUT.replace("move 2 from 2", "move 3 from 2");
+        let error = Replay::parse(lines.lines().map(|s| s.to_string()), &CrateMover9000).err().unwrap();
+        assert_eq!(error.to_string(), "line 8 'move 3 from 2 to 1': cannot take 3 crate(s) from stack 2, which only contains [M] [C]");
+    }
+
+    #[test]
+    fn drawing() {
+        let mut lines = INPUT.lines().map(|s| s.to_string());
+        let stacks = Stacks::parse(&mut lines);
+        let drawing = INPUT.lines().take(4).join("\n");
+        assert_eq!(stacks.to_string(), drawing);
+        let mut stacks = Stacks::new(4);
+        stacks.push(1, "A".into());
+        let drawing = stacks.to_string();
+        assert_eq!(drawing, "    [A]\n 1   2   3   4");
+        assert_eq!(Stacks::parse(&mut drawing.lines().map(|s| s.to_string())), stacks);
+    }
+
+    #[test]
+    fn wide_drawing() {
+        let drawing = "\
+[AB12]        [X]
+[C]    [DE]  [Y]
+  1     2     3";
+        let stacks = Stacks::parse(&mut drawing.lines().map(|s| s.to_string()));
+        assert_eq!(stacks.crates(0), ["C", "AB12"].map(Crate::from));
+        assert_eq!(stacks.crates(1), ["DE"].map(Crate::from));
+        assert_eq!(stacks.crates(2), ["Y", "X"].map(Crate::from));
+        assert_eq!(stacks.to_string(), "\
+[AB12]         [X]
+ [C]    [DE]   [Y]
+  1      2      3");
+
+        let mut stacks = Stacks::new(12);
+        stacks.push(0, "A".into());
+        stacks.push(11, "B".into());
+        stacks.push(11, "C".into());
+        let drawing = stacks.to_string();
+        assert_eq!(drawing, "                                            [C]\n\
+                             [A]                                         [B]\n\
+                             \x201   2   3   4   5   6   7   8   9  10  11  12");
+        let mut lines = drawing.lines().map(|s| s.to_string()).chain(["".to_string(), "move 1 from 12 to 1".to_string()]);
+        assert_eq!(top_crates(&mut lines, &CrateMover9001, Mode::Strict, Verbose::All), Ok("C          B".to_string()));
+    }
+
+    #[test]
+    fn drawing_round_trip() {
+        let mut lines = get_file_lines("data/05_data.txt");
+        let stacks = Stacks::parse(&mut lines);
+        lines.next();
+        let moves = lines.take(50).map(|line| line.parse::<Move>().unwrap());
+        let mut replay = Replay::record(stacks, moves, &CrateMover9001);
+        for position in [0, 10, 50] {
+            let stacks = replay.stacks_at(position);
+            let drawing = stacks.to_string();
+            assert_eq!(&Stacks::parse(&mut drawing.lines().map(|s| s.to_string())), stacks);
+        }
+    }
+
+    #[test]
+    fn invalid_moves() {
+        let input = INPUT.replace("move 2 from 2 to 1", "move 3 from 2 to 1\nmove 1 from 4 to 1\nmove two from 2 to 1");
+        let lines = input.lines().map(|s| s.to_string());
+        let error = top_crates(lines, &CrateMover9000, Mode::Strict, Verbose::Quiet).unwrap_err();
+        assert_eq!(error, MoveError {
+            line: 8,
+            text: "move 3 from 2 to 1".to_string(),
+            kind: MoveErrorKind::NotEnoughCrates { num: 3, stack: 1, crates: labels("MC") }
+        });
+        assert_eq!(error.to_string(), "line 8 'move 3 from 2 to 1': cannot take 3 crate(s) from stack 2, which only contains [M] [C]");
+
+        let mut lines = input.lines().map(|s| s.to_string());
+        let stacks = Stacks::parse(&mut lines);
+        let moves = lines.enumerate().map(|(i, line)| (i + 5, line));
+        let simulation = simulate(stacks, moves, &CrateMover9000, Mode::BestEffort, Verbose::All).unwrap();
+        assert_eq!(simulation.stacks.top(), " CZ");
+        let errors = simulation.skipped.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+        assert_eq!(errors, vec![
+            "line 8 'move 3 from 2 to 1': cannot take 3 crate(s) from stack 2, which only contains [M] [C]",
+            "line 9 'move 1 from 4 to 1': stack 4 doesn't exist, there are 3 stacks",
+            "line 10 'move two from 2 to 1': 'two' is not a number",
+            "line 11 'move 1 from 1 to 2': cannot take 1 crate(s) from stack 1, which is empty",
+        ]);
+    }
+
+    #[test]
+    fn solver() {
+        let mut lines = INPUT.lines().map(|s| s.to_string());
+        let initial = Stacks::parse(&mut lines);
+        lines.next();
+        let moves = lines.enumerate().map(|(i, line)| (i + 6, line));
+        let target = simulate(initial.clone(), moves, &CrateMover9001, Mode::Strict, Verbose::Quiet).unwrap().stacks;
+        let cranes: [&dyn Crane; 3] = [&CrateMover9000, &CrateMover9001, &LimitedCrane { capacity: 2 }];
+        for crane in cranes {
+            let moves = solve(&initial, &target, crane, 100_000).unwrap();
+            let puzzle = write_puzzle(&initial, &moves);
+            let lines = puzzle.lines().map(|s| s.to_string());
+            assert_eq!(top_crates(lines, crane, Mode::Strict, Verbose::All), Ok("MCD".to_string()));
+            let mut replay = Replay::record(initial.clone(), moves.into_iter(), crane);
+            assert_eq!(replay.stacks_at(usize::MAX), &target);
+        }
+        let moves = solve(&initial, &target, &CrateMover9001, 100_000).unwrap();
+        assert!(moves.len() <= 4);
+        assert_eq!(solve(&initial, &initial, &CrateMover9001, 10), Ok(vec![]));
+        assert_eq!(solve(&initial, &Stacks::new(3), &CrateMover9001, 10), Err(SolveError::Incompatible));
+        assert_eq!(solve(&initial, &target, &CrateMover9001, 10), Err(SolveError::TooManyStates(10)));
+
+        // a single stack can't be reordered
+        let mut single = Stacks::new(1);
+        single.extend(0, &labels("AB"));
+        let mut reordered = Stacks::new(1);
+        reordered.extend(0, &labels("BA"));
+        let error = solve(&single, &reordered, &CrateMover9001, 100_000).unwrap_err();
+        assert_eq!(error, SolveError::Unreachable(1));
+        assert_eq!(error.to_string(), "target unreachable, all 1 reachable states explored");
+    }
+
+    /// Previous representation, where the crates are moved one at a time (reference for the comparisons).
+    fn naive_moves(stacks: &mut [VecDeque<Crate>], moves: &[Move], multi: bool) {
+        for mv in moves {
+            let mut chunk = VecDeque::<Crate>::new();
+            for _ in 0..mv.num {
+                let c = stacks[mv.from].pop_back().unwrap();
+                if multi {
+                    chunk.push_front(c);
+                } else {
+                    chunk.push_back(c);
+                }
+            }
+            stacks[mv.to].append(&mut chunk);
+        }
+    }
+
+    /// Generates `num_stacks` stacks of `height` crates and `num_moves` random valid moves.
+    fn random_puzzle(num_stacks: usize, height: usize, num_moves: usize) -> (Vec<VecDeque<Crate>>, Vec<Move>) {
+        let mut seed: u64 = 1;
+        let mut random = |n: usize| {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (seed >> 33) as usize % n
+        };
+        let stacks = (0..num_stacks)
+            .map(|_| (0..height).map(|_| Crate::from(((b'A' + random(26) as u8) as char).to_string())).collect::<VecDeque<_>>())
+            .collect::<Vec<_>>();
+        let mut heights = vec![height; num_stacks];
+        let mut moves = Vec::new();
+        while moves.len() < num_moves {
+            let (from, to) = (random(num_stacks), random(num_stacks));
+            if from != to && heights[from] > 0 {
+                let num = 1 + random(heights[from]);
+                heights[from] -= num;
+                heights[to] += num;
+                moves.push(Move { num, from, to });
+            }
+        }
+        (stacks, moves)
+    }
+
+    fn to_stacks(naive: &[VecDeque<Crate>]) -> Stacks {
+        Stacks { stacks: naive.iter().map(|s| Stack::from_crates(s.iter().cloned().collect())).collect() }
+    }
+
+    #[test]
+    fn block_moves() {
+        let (naive, moves) = random_puzzle(5, 200, 2000);
+        let cranes: [(&dyn Crane, bool); 2] = [(&CrateMover9000, false), (&CrateMover9001, true)];
+        for (crane, multi) in cranes {
+            let initial = to_stacks(&naive);
+            let mut stacks = initial.clone();
+            let mut expected = naive.clone();
+            for (index, mv) in moves.iter().enumerate() {
+                crane.move_crates(&mut stacks, mv, index);
+            }
+            naive_moves(&mut expected, &moves, multi);
+            assert_eq!(stacks, to_stacks(&expected));
+            // the clones share their nodes, but aren't modified by the moves
+            assert_eq!(initial, to_stacks(&naive));
+            for (index, stack) in expected.iter().enumerate() {
+                let top = stack.iter().skip(stack.len().saturating_sub(3)).cloned().collect::<Vec<_>>();
+                assert_eq!(stacks.peek(index, 3), top);
+            }
+            assert_eq!(stacks.top(), expected.iter().map(|s| s.back().map_or(" ", |c| c)).join(""));
+            assert_eq!(stacks.pop(0), expected[0].pop_back());
+        }
+    }
+
+    /// Compares the block moves with the previous representation:
+    /// `cargo test --release bench_block_moves -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_block_moves() {
+        let (naive, moves) = random_puzzle(9, 10_000, 100_000);
+        for (crane, multi) in [(&CrateMover9000 as &dyn Crane, false), (&CrateMover9001, true)] {
+            let mut stacks = to_stacks(&naive);
+            let timer = Instant::now();
+            for (index, mv) in moves.iter().enumerate() {
+                crane.move_crates(&mut stacks, mv, index);
+            }
+            let block_time = timer.elapsed();
+            let mut expected = naive.clone();
+            let timer = Instant::now();
+            naive_moves(&mut expected, &moves, multi);
+            let naive_time = timer.elapsed();
+            println!("{}: blocks {block_time:?}, one at a time {naive_time:?}", crane.name());
+            assert_eq!(stacks, to_stacks(&expected));
+        }
     }
 
     #[test]
@@ -1,47 +1,309 @@
-use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::ops::Range;
+use std::io::{BufReader, Read};
 use itertools::Itertools;
 use crate::get_file_lines;
 
 #[allow(dead_code)]
-
 pub fn day06() {
     let packets = packet_offsets(get_file_lines("data/06_data.txt"))
-        .map(|offset: usize| offset.to_string())
+        .map(|offset| show_offset(&offset))
         .join(", ");
     println!("Packet offsets: {packets}");
     let messages = message_offsets(get_file_lines("data/06_data.txt"))
-        .map(|offset: usize| offset.to_string())
+        .map(|offset| show_offset(&offset))
         .join(", ");
     println!("Message offsets: {messages}");
+    let reports = marker_reports(get_file_lines("data/06_data.txt"), 14)
+        .map(|report| format!("{} markers, longest run {:?}", report.offsets.len(), report.longest))
+        .join(", ");
+    println!("Message markers: {reports}");
+}
+
+/// Reason why a marker wasn't found.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MarkerError {
+    /// the signal has `len` bytes, fewer than the marker's `window_len`
+    TooShort { len: usize, window_len: usize },
+    /// the signal has no marker
+    NotFound
+}
+
+impl Display for MarkerError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MarkerError::TooShort { len, window_len } =>
+                write!(f, "signal too short ({len} bytes for a marker of {window_len})"),
+            MarkerError::NotFound => write!(f, "not found")
+        }
+    }
+}
+
+impl std::error::Error for MarkerError {}
+
+/// Displays an offset, or why it wasn't found.
+fn show_offset(offset: &Result<usize, MarkerError>) -> String {
+    match offset {
+        Ok(offset) => offset.to_string(),
+        Err(e) => e.to_string()
+    }
 }
 
 /// Finds the offset of the packet in each line. The offset is the first
 /// character after the marker, defined as 4 consecutive different characters.
-fn packet_offsets<E>(lines: E) -> impl Iterator<Item = usize>
+fn packet_offsets<E>(lines: E) -> impl Iterator<Item = Result<usize, MarkerError>>
     where E: Iterator<Item = String>
 {
-    lines.map(|line| {
-        line.chars()
-            .tuple_windows()
-            .position(|(a, b, c, d)| a != b && b != c && c != d && a != c && a != d && b != d)
-            .and_then(|x| Some(x + 4))
-            .unwrap_or(0)
-    })
+    lines.map(|line| find_marker(&line, 4))
 }
 
 /// Finds the offset of the message in each line. The offset is the first
 /// character after the marker, defined as 14 consecutive different characters.
-fn message_offsets<E>(lines: E) -> impl Iterator<Item = usize>
+fn message_offsets<E>(lines: E) -> impl Iterator<Item = Result<usize, MarkerError>>
+    where E: Iterator<Item = String>
+{
+    lines.map(|line| find_marker(&line, 14))
+}
+
+/// Finds the offset of the first byte after the marker, defined as `window_len` consecutive
+/// different bytes.
+///
+/// The signal is handled as bytes, like the datastream it comes from: the offsets are in bytes,
+/// and a non-ASCII character counts as each of its bytes.
+pub fn find_marker(input: &str, window_len: usize) -> Result<usize, MarkerError> {
+    find_byte_marker(input.as_bytes(), window_len)
+}
+
+/// `find_marker` on any bytes, which don't have to be valid UTF-8.
+fn find_byte_marker(input: &[u8], window_len: usize) -> Result<usize, MarkerError> {
+    if input.len() < window_len {
+        return Err(MarkerError::TooShort { len: input.len(), window_len });
+    }
+    if window_len == 0 {
+        return Ok(0);
+    }
+    let mut detector = Detector::new();
+    input.iter().position(|&c| detector.push(c) >= window_len)
+        .map(|i| i + 1)
+        .ok_or(MarkerError::NotFound)
+}
+
+/// All the markers of a signal.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MarkerReport {
+    pub window_len: usize,
+    /// offset after each window of `window_len` different bytes, in increasing order
+    pub offsets: Vec<usize>,
+    /// first longest run of different bytes
+    pub longest: Range<usize>
+}
+
+/// Finds all the markers of length `window_len` in `signal`, and its longest run of different bytes.
+pub fn marker_report(signal: &str, window_len: usize) -> MarkerReport {
+    let mut detector = Detector::new();
+    let mut offsets = Vec::new();
+    let mut longest = 0..0;
+    for c in signal.bytes() {
+        let run = detector.push(c);
+        let end = detector.position();
+        if run >= window_len {
+            offsets.push(end);
+        }
+        if run > longest.len() {
+            longest = end - run..end;
+        }
+    }
+    MarkerReport { window_len, offsets, longest }
+}
+
+/// Reports all the markers of length `window_len` in each line.
+fn marker_reports<E>(lines: E, window_len: usize) -> impl Iterator<Item = MarkerReport>
     where E: Iterator<Item = String>
 {
-    lines.map(|line| {
-        (0..=line.len() - 14)
-            .find_map(|i| {
-                let s = &line[i..i+14].chars().collect::<HashSet<_>>();
-                if s.len() == 14 { Some(i + 14) } else { None }
-            })
-            .unwrap_or(0)
-    })
+    lines.map(move |line| marker_report(&line, window_len))
+}
+
+/// Part of a datastream starting with a marker. The datastream is made of bytes, which don't
+/// have to be valid UTF-8 once split.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame<'a> {
+    /// position of the marker in the datastream
+    pub marker: Range<usize>,
+    /// data between the marker and the next one, or the end of the enclosing payload
+    pub payload: &'a [u8],
+    /// frames of the next protocol layer found in the payload
+    pub frames: Vec<Frame<'a>>
+}
+
+/// Iterator over the frames of a datastream, split by markers of `window_len` different bytes.
+/// The data before the first marker is ignored.
+pub struct Decoder<'a> {
+    data: &'a [u8],
+    end: usize,
+    window_len: usize,
+    next_marker: Option<Range<usize>>
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(data: &'a [u8], window_len: usize) -> Self {
+        Decoder::within(data, 0..data.len(), window_len)
+    }
+
+    /// Decodes the frames in `data[range]`. The positions are given in `data`.
+    fn within(data: &'a [u8], range: Range<usize>, window_len: usize) -> Self {
+        assert!(window_len > 0, "markers must have at least 1 byte");
+        let mut decoder = Decoder { data, end: range.end, window_len, next_marker: None };
+        decoder.next_marker = decoder.find_next(range.start);
+        decoder
+    }
+
+    /// Finds the next marker in `data[from..end]`.
+    fn find_next(&self, from: usize) -> Option<Range<usize>> {
+        find_byte_marker(&self.data[from..self.end], self.window_len).ok()
+            .map(|offset| from + offset - self.window_len..from + offset)
+    }
+}
+
+impl<'a> Iterator for Decoder<'a> {
+    type Item = Frame<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let marker = self.next_marker.take()?;
+        self.next_marker = self.find_next(marker.end);
+        let end = self.next_marker.as_ref().map_or(self.end, |m| m.start);
+        Some(Frame { payload: &self.data[marker.end..end], marker, frames: Vec::new() })
+    }
+}
+
+/// Protocol made of nested layers, each one split by markers of a given length. For example,
+/// `Protocol { marker_lens: vec![4, 14] }` is made of packets containing messages.
+#[derive(Clone, Debug)]
+pub struct Protocol {
+    pub marker_lens: Vec<usize>
+}
+
+impl Protocol {
+    /// Splits the datastream into frames of the first layer, their payload into frames of the
+    /// second layer, and so on.
+    pub fn decode<'a>(&self, data: &'a [u8]) -> Vec<Frame<'a>> {
+        self.decode_layer(data, 0..data.len(), 0)
+    }
+
+    fn decode_layer<'a>(&self, data: &'a [u8], range: Range<usize>, layer: usize) -> Vec<Frame<'a>> {
+        match self.marker_lens.get(layer) {
+            Some(&window_len) => Decoder::within(data, range, window_len)
+                .map(|mut frame| {
+                    let payload = frame.marker.end..frame.marker.end + frame.payload.len();
+                    frame.frames = self.decode_layer(data, payload, layer + 1);
+                    frame
+                })
+                .collect(),
+            None => Vec::new()
+        }
+    }
+}
+
+/// Incremental detector of runs of different bytes. The last position of each byte value is
+/// kept in a table, so the length of the current run is updated in constant time and memory.
+#[derive(Clone)]
+pub struct Detector {
+    /// last[c] = 1 + last position of c, or 0 if c hasn't been seen yet
+    last: [usize; 256],
+    /// start of the current run of different bytes
+    start: usize,
+    /// number of bytes pushed so far
+    position: usize
+}
+
+impl Detector {
+    pub fn new() -> Self {
+        Detector { last: [0; 256], start: 0, position: 0 }
+    }
+
+    /// Feeds the next byte, and returns the length of the run of different bytes ending with it.
+    pub fn push(&mut self, c: u8) -> usize {
+        self.start = self.start.max(self.last[c as usize]);
+        self.position += 1;
+        self.last[c as usize] = self.position;
+        self.position - self.start
+    }
+
+    /// Number of bytes pushed so far.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+}
+
+impl Default for Detector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Marker found in a stream of signals.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Marker {
+    /// index of the signal (line) in the stream, starting at 0
+    pub signal: usize,
+    pub window_len: usize,
+    /// offset of the first byte after the marker in the signal
+    pub offset: usize
+}
+
+/// Iterator over the markers of a stream of signals, one signal per line. Only the first marker
+/// of each length is reported for each signal, as soon as it's read.
+pub struct MarkerStream<R: Read> {
+    bytes: io::Bytes<BufReader<R>>,
+    window_lens: Vec<usize>,
+    found: Vec<bool>,
+    detector: Detector,
+    signal: usize,
+    pending: VecDeque<Marker>
+}
+
+/// Detects the markers of lengths `window_lens` in the signals read from `reader`, with a
+/// constant memory footprint.
+pub fn stream_markers<R: Read>(reader: R, window_lens: &[usize]) -> MarkerStream<R> {
+    assert!(window_lens.iter().all(|&n| n > 0), "markers must have at least 1 byte");
+    MarkerStream {
+        bytes: BufReader::new(reader).bytes(),
+        window_lens: window_lens.to_vec(),
+        found: vec![false; window_lens.len()],
+        detector: Detector::new(),
+        signal: 0,
+        pending: VecDeque::new()
+    }
+}
+
+impl<R: Read> Iterator for MarkerStream<R> {
+    type Item = io::Result<Marker>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.pending.is_empty() {
+            match self.bytes.next()? {
+                Err(e) => return Some(Err(e)),
+                Ok(b'\n') => {
+                    self.signal += 1;
+                    self.detector = Detector::new();
+                    self.found.fill(false);
+                }
+                Ok(b'\r') => {}
+                Ok(c) => {
+                    let run = self.detector.push(c);
+                    for (found, &window_len) in self.found.iter_mut().zip(&self.window_lens) {
+                        if !*found && run >= window_len {
+                            *found = true;
+                            let offset = self.detector.position();
+                            self.pending.push_back(Marker { signal: self.signal, window_len, offset });
+                        }
+                    }
+                }
+            }
+        }
+        self.pending.pop_front().map(Ok)
+    }
 }
 
 #[cfg(test)]
@@ -59,7 +321,7 @@ zcfzfwzzqfrljwzlrfnpqdbhtmscgvjw";
         let lines = INPUT.lines().map(|s| s.to_string());
         let offsets = packet_offsets(lines).collect::<Vec<_>>();
         println!("offsets: {offsets:?}");
-        assert_eq!(offsets, vec![7, 5, 6, 10, 11]);
+        assert_eq!(offsets, vec![Ok(7), Ok(5), Ok(6), Ok(10), Ok(11)]);
     }
 
     #[test]
@@ -67,7 +329,93 @@ zcfzfwzzqfrljwzlrfnpqdbhtmscgvjw";
         let lines = INPUT.lines().map(|s| s.to_string());
         let offsets = message_offsets(lines).collect::<Vec<_>>();
         println!("offsets: {offsets:?}");
-        assert_eq!(offsets, vec![19, 23, 23, 29, 26]);
+        assert_eq!(offsets, vec![Ok(19), Ok(23), Ok(23), Ok(29), Ok(26)]);
+    }
+
+    #[test]
+    fn get_missing_markers() {
+        let lines = ["abcb", "ab", "abababab"].map(|s| s.to_string()).into_iter();
+        let offsets = packet_offsets(lines).map(|offset| show_offset(&offset)).collect::<Vec<_>>();
+        assert_eq!(offsets, vec![
+            "not found",
+            "signal too short (2 bytes for a marker of 4)",
+            "not found",
+        ]);
+        let lines = ["abcd"].map(|s| s.to_string()).into_iter();
+        assert_eq!(message_offsets(lines).next(), Some(Err(MarkerError::TooShort { len: 4, window_len: 14 })));
+    }
+
+    #[test]
+    fn get_markers() {
+        assert_eq!(find_marker("abcabcd", 4), Ok(7));
+        assert_eq!(find_marker("abcabcd", 3), Ok(3));
+        assert_eq!(find_marker("aaaa", 1), Ok(1));
+        assert_eq!(find_marker("aaaa", 2), Err(MarkerError::NotFound));
+        assert_eq!(find_marker("", 0), Ok(0));
+        assert_eq!(find_marker("abc", 4), Err(MarkerError::TooShort { len: 3, window_len: 4 }));
+        let alphabet = "abcdefghijklmnopqrstuvwxyz";
+        assert_eq!(find_marker(&format!("{alphabet}{alphabet}"), 26), Ok(26));
+        assert_eq!(find_marker(&format!("aa{alphabet}"), 26), Ok(28));
+        // "é" is made of 2 different bytes
+        assert_eq!(find_marker("éé", 2), Ok(2));
+        assert_eq!(find_marker("aéa", 3), Ok(3));
+        assert_eq!(find_marker("é", 3), Err(MarkerError::TooShort { len: 2, window_len: 3 }));
+    }
+
+    #[test]
+    fn get_stream_markers() {
+        let markers = stream_markers(INPUT.as_bytes(), &[4, 14])
+            .map(|m| m.unwrap())
+            .collect::<Vec<_>>();
+        let packets = markers.iter().filter(|m| m.window_len == 4).map(|m| m.offset).collect::<Vec<_>>();
+        let messages = markers.iter().filter(|m| m.window_len == 14).map(|m| m.offset).collect::<Vec<_>>();
+        assert_eq!(packets, vec![7, 5, 6, 10, 11]);
+        assert_eq!(messages, vec![19, 23, 23, 29, 26]);
+        assert_eq!(markers[0], Marker { signal: 0, window_len: 4, offset: 7 });
+        assert_eq!(markers[1], Marker { signal: 0, window_len: 14, offset: 19 });
+        let markers = stream_markers("abab\r\nabc".as_bytes(), &[1, 3]).map(|m| m.unwrap()).collect::<Vec<_>>();
+        assert_eq!(markers, vec![
+            Marker { signal: 0, window_len: 1, offset: 1 },
+            Marker { signal: 1, window_len: 1, offset: 1 },
+            Marker { signal: 1, window_len: 3, offset: 3 },
+        ]);
+    }
+
+    #[test]
+    fn get_reports() {
+        let lines = INPUT.lines().map(|s| s.to_string());
+        let reports = marker_reports(lines, 4).collect::<Vec<_>>();
+        let first = reports.iter().map(|r| r.offsets[0]).collect::<Vec<_>>();
+        assert_eq!(first, vec![7, 5, 6, 10, 11]);
+        assert_eq!(marker_report("abcabcdd", 3), MarkerReport { window_len: 3, offsets: vec![3, 4, 5, 6, 7], longest: 3..7 });
+        assert_eq!(marker_report("aabb", 3), MarkerReport { window_len: 3, offsets: vec![], longest: 1..3 });
+        assert_eq!(marker_report("", 3), MarkerReport { window_len: 3, offsets: vec![], longest: 0..0 });
+    }
+
+    #[test]
+    fn decode() {
+        let data = b"abcaabbxxxyzddee";
+        let frames = Decoder::new(data, 3).collect::<Vec<_>>();
+        assert_eq!(frames, vec![
+            Frame { marker: 0..3, payload: b"aabbxx", frames: vec![] },
+            Frame { marker: 9..12, payload: b"ddee", frames: vec![] },
+        ]);
+        let protocol = Protocol { marker_lens: vec![3, 2] };
+        let frames = protocol.decode(data);
+        assert_eq!(frames[0].frames, vec![
+            Frame { marker: 4..6, payload: b"", frames: vec![] },
+            Frame { marker: 6..8, payload: b"x", frames: vec![] },
+        ]);
+        assert_eq!(frames[1].frames, vec![
+            Frame { marker: 13..15, payload: b"e", frames: vec![] },
+        ]);
+        assert_eq!(Decoder::new(b"aaaa", 2).count(), 0);
+        // the markers can split a multi-byte character
+        let frames = Decoder::new("aé".as_bytes(), 2).collect::<Vec<_>>();
+        assert_eq!(frames, vec![Frame { marker: 0..2, payload: b"\xa9", frames: vec![] }]);
+        let frames = Protocol { marker_lens: vec![2, 1] }.decode("xé".as_bytes());
+        assert_eq!(frames[0].payload, b"\xa9");
+        assert_eq!(frames[0].frames, vec![Frame { marker: 2..3, payload: b"", frames: vec![] }]);
     }
 
     #[test]
@@ -1,126 +1,1472 @@
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::rc::Rc;
+use std::str::FromStr;
+use serde::de::Error as _;
+use serde::{Deserialize, Serialize};
 use crate::get_file_lines;
 
-#[allow(dead_code)]
+pub fn day07() {
+    let root = parse(get_file_lines("data/07_data.txt"));
+    print_answers(&root, &DiskConfig::default());
+}
 
-const THRESHOLD: u32     =    100_000;
-const DISK_CAPACITY: u32 = 70_000_000;
-const UPDATE_SIZE: u32   = 30_000_000;
+/// Prints the answers of both parts for the tree `root` on the disk described by `config`.
+pub fn print_answers(root: &Node, config: &DiskConfig) {
+    match find_small_dirs(root, config, false) {
+        Some(total) => println!("1) Size small directories: {total}"),
+        None => println!("1) the size of the small directories overflows")
+    }
+    match select_dir(root, config, false) {
+        Some(size) => println!("2) Minimum saved size: {size}"),
+        None => println!("2) not enough space on the disk")
+    }
+}
 
-pub fn day07() {
-    let total = find_small_dirs(get_file_lines("data/07_data.txt"), false);
-    println!("1) Size small directories: {total}");
-    let size = select_dir(get_file_lines("data/07_data.txt"), false);
-    println!("2) Minimum saved size: {size}");
+/// Disk parameters used by the analyses, in bytes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DiskConfig {
+    /// total size of the disk
+    pub capacity: u64,
+    /// free space required by the update
+    pub required: u64,
+    /// maximum size of the directories summed by `find_small_dirs`
+    pub threshold: u64
 }
 
-#[derive(Debug)]
-struct Node {
+impl Default for DiskConfig {
+    /// The puzzle's parameters.
+    fn default() -> Self {
+        DiskConfig { capacity: 70_000_000, required: 30_000_000, threshold: 100_000 }
+    }
+}
+
+/// Error in a disk configuration, with the faulty text.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DiskConfigError(pub String);
+
+impl Display for DiskConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid disk parameter '{}'", self.0)
+    }
+}
+
+impl std::error::Error for DiskConfigError {}
+
+impl FromStr for DiskConfig {
+    type Err = DiskConfigError;
+
+    /// Parses comma-separated parameters like `capacity=80000000,required=30000000`, as given
+    /// on a command line. The missing parameters keep their default value.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = DiskConfig::default();
+        for param in s.split(',').map(|p| p.trim()).filter(|p| !p.is_empty()) {
+            let error = || DiskConfigError(param.to_string());
+            let (key, value) = param.split_once('=').ok_or_else(error)?;
+            let value = value.trim().replace('_', "").parse::<u64>().map_err(|_| error())?;
+            match key.trim() {
+                "capacity" => config.capacity = value,
+                "required" => config.required = value,
+                "threshold" => config.threshold = value,
+                _ => return Err(error())
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// File entry of a directory.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct File {
     pub name: String,
-    pub size: u32,
-    pub dirs: Vec<Node>
+    pub size: u64
+}
+
+/// Directory entry, with its total size (the size of all the files it contains, including
+/// those in its subdirectories).
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Node {
+    pub name: String,
+    pub size: u64,
+    pub dirs: Vec<Node>,
+    pub files: Vec<File>
+}
+
+/// Entry found at a given path.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Entry<'a> {
+    Dir(&'a Node),
+    File(&'a File)
+}
+
+impl Entry<'_> {
+    pub fn name(&self) -> &str {
+        match self {
+            Entry::Dir(node) => &node.name,
+            Entry::File(file) => &file.name
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        match self {
+            Entry::Dir(node) => node.size,
+            Entry::File(file) => file.size
+        }
+    }
+}
+
+/// Path of the entry `name` in the directory at `path`.
+pub fn join_path(path: &str, name: &str) -> String {
+    if path.ends_with('/') { format!("{path}{name}") } else { format!("{path}/{name}") }
 }
 
 impl Node {
     pub fn new(name: String) -> Self {
-        Node { name, size: 0, dirs: Vec::new() }
+        Node { name, size: 0, dirs: Vec::new(), files: Vec::new() }
+    }
+
+    /// Subdirectory `name`.
+    pub fn dir(&self, name: &str) -> Option<&Node> {
+        self.dirs.iter().find(|d| d.name == name)
+    }
+
+    /// File `name`.
+    pub fn file(&self, name: &str) -> Option<&File> {
+        self.files.iter().find(|f| f.name == name)
+    }
+
+    /// Entries of the directory, the subdirectories first.
+    pub fn entries(&self) -> impl Iterator<Item = Entry<'_>> {
+        self.dirs.iter().map(Entry::Dir).chain(self.files.iter().map(Entry::File))
+    }
+
+    /// Finds the entry at `path`, relative to this directory. A leading `/` is ignored,
+    /// so absolute paths can be resolved from the root.
+    pub fn get(&self, path: &str) -> Option<Entry<'_>> {
+        let mut names = path.split('/').filter(|n| !n.is_empty()).peekable();
+        let mut node = self;
+        while let Some(name) = names.next() {
+            if names.peek().is_none() {
+                if let Some(file) = node.file(name) {
+                    return Some(Entry::File(file));
+                }
+            }
+            node = node.dir(name)?;
+        }
+        Some(Entry::Dir(node))
     }
 
     /// Walks through all the nodes and executes `f` on each of them.
     pub fn walk<F>(&self, mut f: F) where F: FnMut(&Node) {
         let mut stack = Vec::<&Node>::new();
         stack.push(self);
-        while !stack.is_empty() {
-            let node = stack.pop().unwrap();
+        while let Some(node) = stack.pop() {
             f(node);
             for subnode in node.dirs.iter().rev() {
                 stack.push(subnode)
             }
         }
     }
+
+    /// Walks through all the nodes like `walk`, giving the path of each node to `f`.
+    /// This node is considered as the root, `/`.
+    pub fn walk_paths<'a, F>(&'a self, mut f: F) where F: FnMut(&str, &'a Node) {
+        let mut stack = vec![("/".to_string(), self)];
+        while let Some((path, node)) = stack.pop() {
+            f(&path, node);
+            for subnode in node.dirs.iter().rev() {
+                stack.push((join_path(&path, &subnode.name), subnode))
+            }
+        }
+    }
+
+    /// Executes `f` on all the files with their path, this node being considered as the root.
+    pub fn walk_files<F>(&self, mut f: F) where F: FnMut(&str, &File) {
+        self.walk_paths(|path, node| {
+            for file in &node.files {
+                f(&join_path(path, &file.name), file);
+            }
+        });
+    }
+}
+
+/// Inconsistency found in a transcript, which doesn't prevent building the tree.
+/// The line numbers start at 1.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Warning {
+    /// directory listed again by `ls`
+    Relisted { line: usize, path: String },
+    /// file listed again with the same size, which isn't counted twice
+    Duplicate { line: usize, path: String },
+    /// file listed again with another size, which replaces the previous one
+    Resized { line: usize, path: String, old: u64, new: u64 },
+    /// `rm` of an entry which doesn't exist, or of the root, which is ignored
+    NotRemoved { line: usize, path: String },
+    /// file which would make the size of a directory overflow, which is ignored
+    Overflow { line: usize, path: String },
+    /// file line which isn't a size followed by a name, which is ignored
+    BadLine { line: usize, text: String },
+    /// directory more than `MAX_DEPTH` levels below the root, which is ignored with its content
+    TooDeep { line: usize, path: String }
+}
+
+/// Directory sizes which can't be trusted.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SizeError {
+    /// the total size of the directory at `path` doesn't fit in 64 bits
+    Overflow { path: String },
+    /// directory whose size isn't the total size of its content
+    Mismatch { path: String, stored: u64, computed: u64 }
+}
+
+impl Display for SizeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SizeError::Overflow { path } => write!(f, "size of {path} overflows"),
+            SizeError::Mismatch { path, stored, computed } =>
+                write!(f, "size of {path} is {stored}, but its content has a size of {computed}")
+        }
+    }
+}
+
+impl std::error::Error for SizeError {}
+
+/// Error when a tree has a directory more than `MAX_DEPTH` levels below the root.
+#[derive(Debug, PartialEq)]
+pub struct DepthError {
+    /// path of the first directory too deep
+    pub path: String
+}
+
+impl Display for DepthError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} is more than {MAX_DEPTH} levels deep", self.path)
+    }
+}
+
+impl std::error::Error for DepthError {}
+
+/// Resolves `path` against the current directory `cwd`, given as a list of names from the root.
+/// The path can be absolute or relative, and contain `.` and `..`.
+pub fn resolve_path(cwd: &[String], path: &str) -> Vec<String> {
+    let mut names = if path.starts_with('/') { Vec::new() } else { cwd.to_vec() };
+    for name in path.split('/') {
+        match name {
+            "" | "." => {}
+            ".." => { names.pop(); }
+            _ => names.push(name.to_string())
+        }
+    }
+    names
+}
+
+/// Path of a directory given as a list of names from the root.
+fn path_string(names: &[String]) -> String {
+    format!("/{}", names.join("/"))
+}
+
+impl Node {
+    /// Subdirectory `name`, which is created if it doesn't exist.
+    fn dir_or_create(&mut self, name: &str) -> &mut Node {
+        match self.dirs.iter().position(|d| d.name == name) {
+            Some(index) => &mut self.dirs[index],
+            None => {
+                self.dirs.push(Node::new(name.to_string()));
+                self.dirs.last_mut().unwrap()
+            }
+        }
+    }
+
+    /// Creates the directory at `path`, given as a list of names from this node, and its parents if necessary.
+    pub fn mkdir(&mut self, path: &[String]) -> &mut Node {
+        path.iter().fold(self, |node, name| node.dir_or_create(name))
+    }
+
+    /// Adds `file` in the directory at `path` (created if necessary), or replaces the file with the
+    /// same name, and updates the size of the directories. Returns the replaced file, if any.
+    ///
+    /// If the size of a directory would overflow (or is smaller than the replaced file), the tree
+    /// is left unchanged and the first such directory is reported.
+    pub fn insert_file(&mut self, path: &[String], file: File) -> Result<Option<File>, SizeError> {
+        let old_size = path.iter().try_fold(&*self, |node, name| node.dir(name))
+            .and_then(|dir| dir.file(&file.name))
+            .map_or(0, |f| f.size);
+        let mut dir = Some(&*self);
+        for depth in 0..=path.len() {
+            // the directories which don't exist yet will have the size of the file
+            let Some(node) = dir else { break };
+            if node.size.checked_sub(old_size).and_then(|s| s.checked_add(file.size)).is_none() {
+                return Err(SizeError::Overflow { path: path_string(&path[..depth]) });
+            }
+            dir = path.get(depth).and_then(|name| node.dir(name));
+        }
+        // can't overflow, checked above; the directories are created with a size of 0
+        let mut node = self;
+        for name in path {
+            node.size = node.size - old_size + file.size;
+            node = node.dir_or_create(name);
+        }
+        node.size = node.size - old_size + file.size;
+        match node.files.iter_mut().find(|f| f.name == file.name) {
+            Some(old) => Ok(Some(std::mem::replace(old, file))),
+            None => {
+                node.files.push(file);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Removes the file or directory at `path`, given as a list of names from this node, and
+    /// updates the size of the directories. Returns the size of the removed entry, or `None`
+    /// if it doesn't exist. This node itself can't be removed.
+    pub fn remove(&mut self, path: &[String]) -> Option<u64> {
+        let (name, parent) = path.split_last()?;
+        let dir = parent.iter().try_fold(&*self, |node, name| node.dir(name))?;
+        let size = match dir.file(name) {
+            Some(file) => file.size,
+            None => dir.dir(name)?.size
+        };
+        // the entry exists, as found above; the size of a directory is at least the size of its
+        // content, unless the tree is inconsistent, as reported by check_sizes
+        let mut node = self;
+        for name in parent {
+            node.size = node.size.saturating_sub(size);
+            node = node.dirs.iter_mut().find(|d| &d.name == name).unwrap();
+        }
+        node.size = node.size.saturating_sub(size);
+        match node.files.iter().position(|f| &f.name == name) {
+            Some(index) => { node.files.remove(index); }
+            None => {
+                let index = node.dirs.iter().position(|d| &d.name == name).unwrap();
+                node.dirs.remove(index);
+            }
+        }
+        Some(size)
+    }
+
+    /// Applies the commands of a transcript to this tree, taken as the root, and returns the
+    /// inconsistencies found. The sizes of the directories are updated as the files are added
+    /// or removed, so successive transcripts can be applied without rebuilding the tree.
+    ///
+    /// The transcript starts in the root. Besides `cd` and `ls`, it can use `rm path` to remove
+    /// a file or a directory; the entries missing from an `ls` listing aren't removed. The
+    /// directories deeper than `MAX_DEPTH` aren't created.
+    pub fn apply<E>(&mut self, lines: E) -> Vec<Warning>
+        where E: Iterator<Item = String>
+    {
+        let mut warnings = Vec::new();
+        let mut cwd: Vec<String> = Vec::new();
+        let mut listed = HashSet::<Vec<String>>::new();
+        for (i, line) in lines.enumerate() {
+            if let Some(path) = line.strip_prefix("$ cd ") {
+                cwd = resolve_path(&cwd, path.trim());
+                if cwd.len() > MAX_DEPTH {
+                    warnings.push(Warning::TooDeep { line: i + 1, path: path_string(&cwd) });
+                } else {
+                    self.mkdir(&cwd);
+                }
+            } else if cwd.len() > MAX_DEPTH && !line.starts_with("$ rm ") {
+                // listing of a directory which isn't in the tree, already reported by cd
+            } else if line.starts_with("$ ls") {
+                if !listed.insert(cwd.clone()) {
+                    warnings.push(Warning::Relisted { line: i + 1, path: path_string(&cwd) });
+                }
+            } else if let Some(path) = line.strip_prefix("$ rm ") {
+                let path = resolve_path(&cwd, path.trim());
+                if self.remove(&path).is_none() {
+                    warnings.push(Warning::NotRemoved { line: i + 1, path: path_string(&path) });
+                }
+            } else if let Some(name) = line.strip_prefix("dir ") {
+                if cwd.len() == MAX_DEPTH {
+                    warnings.push(Warning::TooDeep { line: i + 1, path: join_path(&path_string(&cwd), name) });
+                } else {
+                    self.mkdir(&cwd).dir_or_create(name);
+                }
+            } else if line.starts_with(|c: char| c.is_ascii_digit()) {
+                let file = line.split_once(' ')
+                    .filter(|(_, name)| !name.is_empty())
+                    .and_then(|(size, name)| Some(File { name: name.to_string(), size: size.parse().ok()? }));
+                let Some(file) = file else {
+                    warnings.push(Warning::BadLine { line: i + 1, text: line });
+                    continue;
+                };
+                let path = join_path(&path_string(&cwd), &file.name);
+                let new = file.size;
+                match self.insert_file(&cwd, file) {
+                    Ok(Some(old)) if old.size == new => warnings.push(Warning::Duplicate { line: i + 1, path }),
+                    Ok(Some(old)) => warnings.push(Warning::Resized { line: i + 1, path, old: old.size, new }),
+                    Ok(None) => {}
+                    Err(_) => warnings.push(Warning::Overflow { line: i + 1, path })
+                }
+            }
+        }
+        warnings
+    }
+
+    /// Checks that no directory is more than `MAX_DEPTH` levels below this node. The tree is
+    /// walked with a stack of the directories left to visit at each level, as it may be too deep
+    /// for a recursive walk.
+    pub fn check_depth(&self) -> Result<(), DepthError> {
+        let mut open = vec![self.dirs.iter()];
+        let mut names = Vec::new();
+        while let Some(dirs) = open.last_mut() {
+            match dirs.next() {
+                Some(dir) => {
+                    names.push(dir.name.clone());
+                    if open.len() > MAX_DEPTH {
+                        return Err(DepthError { path: path_string(&names) });
+                    }
+                    open.push(dir.dirs.iter());
+                }
+                None => {
+                    open.pop();
+                    names.pop();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that the size of each directory is the total size of its content.
+    pub fn check_sizes(&self) -> Result<(), SizeError> {
+        let mut result = Ok(());
+        self.walk_paths(|path, node| {
+            if result.is_err() {
+                return;
+            }
+            let computed = node.files.iter().map(|f| f.size)
+                .chain(node.dirs.iter().map(|d| d.size))
+                .try_fold(0, u64::checked_add);
+            result = match computed {
+                None => Err(SizeError::Overflow { path: path.to_string() }),
+                Some(computed) if computed != node.size =>
+                    Err(SizeError::Mismatch { path: path.to_string(), stored: node.size, computed }),
+                Some(_) => Ok(())
+            };
+        });
+        result
+    }
+}
+
+/// Scans the directory at `path` and its subdirectories, returning the root of the same
+/// structure as `parse`. The symbolic links aren't followed, and the entries are sorted by name.
+/// A directory more than `MAX_DEPTH` levels deep is reported as invalid data.
+pub fn scan_dir<P: AsRef<Path>>(path: P) -> io::Result<Node> {
+    fn scan(path: &Path, tree_path: &str, depth: usize, node: &mut Node) -> io::Result<()> {
+        if depth > MAX_DEPTH {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, DepthError { path: tree_path.to_string() }));
+        }
+        let mut entries = fs::read_dir(path)?.collect::<io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|e| e.file_name());
+        for entry in entries {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let file_type = entry.file_type()?;
+            let size = if file_type.is_dir() {
+                let mut dir = Node::new(name);
+                scan(&entry.path(), &join_path(tree_path, &dir.name), depth + 1, &mut dir)?;
+                let size = dir.size;
+                node.dirs.push(dir);
+                size
+            } else if file_type.is_file() {
+                let size = entry.metadata()?.len();
+                node.files.push(File { name, size });
+                size
+            } else {
+                0
+            };
+            node.size = node.size.checked_add(size).ok_or_else(||
+                io::Error::new(io::ErrorKind::InvalidData, SizeError::Overflow { path: tree_path.to_string() })
+            )?;
+        }
+        Ok(())
+    }
+    let mut root = Node::new("/".to_string());
+    scan(path.as_ref(), "/", 0, &mut root)?;
+    Ok(root)
+}
+
+/// Order in which `transcript` visits the directories.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Order {
+    /// depth-first, with `cd name` and `cd ..` like the puzzle's transcripts
+    DepthFirst,
+    /// breadth-first, with `cd /absolute/path`
+    BreadthFirst
+}
+
+/// Generates the transcript of the commands listing the whole tree, which can be read back by `parse`.
+/// Each directory lists its subdirectories first, then its files.
+pub fn transcript(root: &Node, order: Order) -> Vec<String> {
+    fn list(node: &Node, lines: &mut Vec<String>) {
+        lines.push("$ ls".to_string());
+        lines.extend(node.dirs.iter().map(|d| format!("dir {}", d.name)));
+        lines.extend(node.files.iter().map(|f| format!("{} {}", f.size, f.name)));
+    }
+    fn depth_first(node: &Node, lines: &mut Vec<String>) {
+        list(node, lines);
+        for dir in &node.dirs {
+            lines.push(format!("$ cd {}", dir.name));
+            depth_first(dir, lines);
+            lines.push("$ cd ..".to_string());
+        }
+    }
+    let mut lines = vec!["$ cd /".to_string()];
+    match order {
+        Order::DepthFirst => {
+            depth_first(root, &mut lines);
+            // no need to go back up at the end
+            while lines.last().is_some_and(|l| l == "$ cd ..") {
+                lines.pop();
+            }
+        }
+        Order::BreadthFirst => {
+            let mut queue = VecDeque::from([("/".to_string(), root)]);
+            while let Some((path, node)) = queue.pop_front() {
+                if path != "/" {
+                    lines.push(format!("$ cd {path}"));
+                }
+                list(node, &mut lines);
+                queue.extend(node.dirs.iter().map(|d| (join_path(&path, &d.name), d)));
+            }
+        }
+    }
+    lines
 }
 
 /// Parses the commands and builds the directory structure, returning its root.
 fn parse<E>(lines: E) -> Node
     where E: Iterator<Item = String>
 {
-    // cd /             -> stack = (/, 0, [])
-    // cd a             -> stack = (/, 0, []), (a, 0, [])
-    // 1                -> stack = (/, 0, []), (a, 1, [])
-    // 2                -> stack = (/, 0, []), (a, 3, [])
-    // cd a1            -> stack = (/, 0, []), (a, 3, []), (a1, 0, [])
-    // 4                -> stack = (/, 0, []), (a, 3, []), (a1, 4, [])
-    // cd ..            -> stack = (/, 0, []), (a, 7, [a1])
-    // cd ..            -> stack = (/, 7, [])
-    let mut stack: Vec<Node> = Vec::new();
-    for mut line in lines {
-        if line.starts_with("$ cd ") {
-            let name = line.split_off(5);
-            match name.as_str() {
-                ".." => {
-                    let node = stack.pop().unwrap();
-                    let parent = stack.last_mut().unwrap();
-                    parent.size += node.size;
-                    parent.dirs.push(node);
-                }
-                _ => {
-                    stack.push(Node::new(name));
-                }
-            }
-        } else if line.starts_with(|c: char| c.is_ascii_digit()) {
-            let mut node = stack.last_mut().unwrap();
-            node.size += line.split_whitespace().next().unwrap().parse::<u32>().unwrap();
-        }
-    }
-    while stack.len() > 1 {
-        let node = stack.pop().unwrap();
-        let parent = stack.last_mut().unwrap();
-        parent.size += node.size;
-        parent.dirs.push(node);
-    }
-    stack.pop().unwrap()
-}
-
-/// Sum of all directories with a total size of at most `THRESHOLD`, including possible overlaps.
-fn find_small_dirs<E>(lines: E, verbose: bool) -> u32
+    parse_with_warnings(lines).0
+}
+
+/// Parses the commands and builds the directory structure, returning its root and the
+/// inconsistencies found in the transcript.
+///
+/// The `cd` paths are resolved against the current directory, so `cd /`, `cd a/b` or
+/// going back to a directory already visited don't create duplicate directories.
+pub fn parse_with_warnings<E>(lines: E) -> (Node, Vec<Warning>)
     where E: Iterator<Item = String>
 {
-    let root = parse(lines);
-    let mut total = 0;
-    root.walk(|n| {
-        if verbose {
-            println!("- {}: {}", n.name, n.size);
+    let mut root = Node::new("/".to_string());
+    let warnings = root.apply(lines);
+    (root, warnings)
+}
+
+/// Error when reading back a tree exported by `Node::to_json` or `Node::to_bytes`.
+#[derive(Debug)]
+pub enum ImportError {
+    /// invalid JSON, or JSON not following the schema
+    Json(serde_json::Error),
+    /// invalid binary data, with the offset where the problem was found
+    Binary(usize),
+    /// inconsistent or overflowing directory sizes
+    Size(SizeError)
+}
+
+impl Display for ImportError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImportError::Json(e) => write!(f, "invalid JSON tree: {e}"),
+            ImportError::Binary(offset) => write!(f, "invalid binary tree at offset {offset}"),
+            ImportError::Size(e) => write!(f, "{e}")
+        }
+    }
+}
+
+impl std::error::Error for ImportError {}
+
+/// Maximum depth of a tree, below the root, enforced when it's built from a transcript or a
+/// directory, exported and imported. A path of `PATH_MAX` (4096) bytes on Linux has fewer levels,
+/// and deeper trees would make the recursive functions overflow the stack.
+pub const MAX_DEPTH: usize = 4096;
+
+/// Rejects JSON nested deeper than a tree of `MAX_DEPTH` levels before parsing it: a
+/// directory at depth `d` is an object nested `2d + 1` levels deep, its lists of directories and
+/// files one level deeper and its files two levels deeper. The check is done on the text because
+/// serde_json would have to go back up through every level to report the error.
+fn check_json_depth(json: &str) -> Result<(), ImportError> {
+    let (mut nesting, mut in_string, mut escaped) = (0usize, false, false);
+    for byte in json.bytes() {
+        if in_string {
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match byte {
+            b'"' => in_string = true,
+            b'{' | b'[' => nesting += 1,
+            b'}' | b']' => nesting = nesting.saturating_sub(1),
+            _ => {}
         }
-        if n.size <= THRESHOLD {
-            total += n.size
+        if nesting > 2 * MAX_DEPTH + 3 {
+            let message = format!("tree deeper than {MAX_DEPTH} levels");
+            return Err(ImportError::Json(serde_json::Error::custom(message)));
         }
-    });
-    total
+    }
+    Ok(())
 }
 
-fn select_dir<E>(lines: E, verbose: bool) -> u32
-    where E: Iterator<Item = String>
-{
-    let root = parse(lines);
+/// Header of the binary export, with the version of the format.
+const BINARY_MAGIC: &[u8; 4] = b"D7T\x01";
+
+/// Reader of the binary export, keeping track of the offset for the errors.
+struct ByteReader<'a> {
+    bytes: &'a [u8],
+    offset: usize
+}
+
+impl ByteReader<'_> {
+    fn byte(&mut self) -> Result<u8, ImportError> {
+        let byte = *self.bytes.get(self.offset).ok_or(ImportError::Binary(self.offset))?;
+        self.offset += 1;
+        Ok(byte)
+    }
+
+    /// Unsigned LEB128 number.
+    fn number(&mut self) -> Result<u64, ImportError> {
+        let start = self.offset;
+        let mut n = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            n |= ((byte & 0x7f) as u64).checked_shl(shift).filter(|x| x >> shift == (byte & 0x7f) as u64)
+                .ok_or(ImportError::Binary(start))?;
+            if byte & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+        Err(ImportError::Binary(start))
+    }
+
+    /// Length-prefixed UTF-8 text.
+    fn text(&mut self) -> Result<String, ImportError> {
+        let start = self.offset;
+        let len = usize::try_from(self.number()?).map_err(|_| ImportError::Binary(start))?;
+        let bytes = self.offset.checked_add(len)
+            .and_then(|end| self.bytes.get(self.offset..end))
+            .ok_or(ImportError::Binary(start))?;
+        let text = String::from_utf8(bytes.to_vec()).map_err(|_| ImportError::Binary(start))?;
+        self.offset += len;
+        Ok(text)
+    }
+
+    /// Tree with the sizes of its directories. The directories are read with an explicit stack
+    /// of the ones still open, with their path and number of subdirectories left to read.
+    fn tree(&mut self) -> Result<Node, ImportError> {
+        let mut open: Vec<(Node, String, u64)> = Vec::new();
+        loop {
+            if open.len() > MAX_DEPTH {
+                return Err(ImportError::Binary(self.offset));
+            }
+            let mut node = Node::new(self.text()?);
+            let path = open.last().map_or("/".to_string(), |(_, parent, _)| join_path(parent, &node.name));
+            for _ in 0..self.number()? {
+                let file = File { name: self.text()?, size: self.number()? };
+                node.size = node.size.checked_add(file.size)
+                    .ok_or_else(|| ImportError::Size(SizeError::Overflow { path: path.clone() }))?;
+                node.files.push(file);
+            }
+            let dirs = self.number()?;
+            open.push((node, path, dirs));
+            // closes the directories without subdirectories left to read
+            while let Some((_, _, 0)) = open.last() {
+                let (dir, _, _) = open.pop().unwrap();
+                let Some((parent, path, _)) = open.last_mut() else { return Ok(dir) };
+                parent.size = parent.size.checked_add(dir.size)
+                    .ok_or_else(|| ImportError::Size(SizeError::Overflow { path: path.clone() }))?;
+                parent.dirs.push(dir);
+            }
+            open.last_mut().unwrap().2 -= 1;
+        }
+    }
+}
+
+impl Node {
+    /// Tree as JSON, to be inspected by other tools. Each directory is an object with its
+    /// `name`, total `size`, subdirectories `dirs` and `files`, and each file an object with
+    /// its `name` and `size`. A tree deeper than `MAX_DEPTH` isn't exported, as it couldn't be
+    /// read back.
+    pub fn to_json(&self) -> Result<String, DepthError> {
+        self.check_depth()?;
+        // the stack grows on demand, so deep trees don't overflow it
+        let mut json = Vec::new();
+        let mut serializer = serde_json::Serializer::pretty(&mut json);
+        self.serialize(serde_stacker::Serializer::new(&mut serializer)).unwrap();
+        Ok(String::from_utf8(json).unwrap())
+    }
+
+    /// Reads back a tree exported by `to_json`, checking the sizes of the directories.
+    pub fn from_json(json: &str) -> Result<Node, ImportError> {
+        // the depth is limited by check_json_depth rather than by serde_json's recursion limit,
+        // which only allows about 64 levels, and the stack grows on demand
+        check_json_depth(json)?;
+        let mut deserializer = serde_json::Deserializer::from_str(json);
+        deserializer.disable_recursion_limit();
+        let root = Node::deserialize(serde_stacker::Deserializer::new(&mut deserializer))
+            .and_then(|root| deserializer.end().map(|_| root))
+            .map_err(ImportError::Json)?;
+        root.check_sizes().map_err(ImportError::Size)?;
+        Ok(root)
+    }
+
+    /// Tree in a compact binary form: a header, then each directory with its name, its files
+    /// with their name and size, and its subdirectories. The numbers are LEB128-encoded and
+    /// the names prefixed by their length; the sizes of the directories are left out. A tree
+    /// deeper than `MAX_DEPTH` isn't exported, as it couldn't be read back.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, DepthError> {
+        self.check_depth()?;
+        fn number(bytes: &mut Vec<u8>, mut n: u64) {
+            while n >= 0x80 {
+                bytes.push(n as u8 | 0x80);
+                n >>= 7;
+            }
+            bytes.push(n as u8);
+        }
+        fn text(bytes: &mut Vec<u8>, s: &str) {
+            number(bytes, s.len() as u64);
+            bytes.extend_from_slice(s.as_bytes());
+        }
+        fn node(bytes: &mut Vec<u8>, dir: &Node) {
+            text(bytes, &dir.name);
+            number(bytes, dir.files.len() as u64);
+            for file in &dir.files {
+                text(bytes, &file.name);
+                number(bytes, file.size);
+            }
+            number(bytes, dir.dirs.len() as u64);
+            for subdir in &dir.dirs {
+                node(bytes, subdir);
+            }
+        }
+        let mut bytes = BINARY_MAGIC.to_vec();
+        node(&mut bytes, self);
+        Ok(bytes)
+    }
+
+    /// Reads back a tree exported by `to_bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Node, ImportError> {
+        if !bytes.starts_with(BINARY_MAGIC) {
+            return Err(ImportError::Binary(0));
+        }
+        let mut reader = ByteReader { bytes, offset: BINARY_MAGIC.len() };
+        let root = reader.tree()?;
+        if reader.offset != bytes.len() {
+            return Err(ImportError::Binary(reader.offset));
+        }
+        Ok(root)
+    }
+}
+
+/// Size of a directory which differs between two snapshots of the tree. A directory which
+/// only exists in one of them has a size of 0 in the other.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SizeChange {
+    pub path: String,
+    pub old: u64,
+    pub new: u64
+}
+
+impl SizeChange {
+    pub fn grown(&self) -> bool {
+        self.new > self.old
+    }
+}
+
+impl Display for SizeChange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let sign = if self.grown() { '+' } else { '-' };
+        write!(f, "{sign}{} {} ({} -> {})", self.new.abs_diff(self.old), self.path, self.old, self.new)
+    }
+}
+
+/// Directories which have grown or shrunk between the snapshots `old` and `new`, sorted by path.
+pub fn diff(old: &Node, new: &Node) -> Vec<SizeChange> {
+    let mut sizes = BTreeMap::<String, (u64, u64)>::new();
+    old.walk_paths(|path, node| sizes.entry(path.to_string()).or_default().0 = node.size);
+    new.walk_paths(|path, node| sizes.entry(path.to_string()).or_default().1 = node.size);
+    sizes.into_iter()
+        .filter(|(_, (old, new))| old != new)
+        .map(|(path, (old, new))| SizeChange { path, old, new })
+        .collect()
+}
+
+/// Size in human-readable units (powers of 1024), like `du -h`: `584B`, `2.5K`, `47M`. As with
+/// `du -h`, the size is rounded up, to one decimal below 10.
+pub fn human_size(size: u64) -> String {
+    let units = ["B", "K", "M", "G", "T", "P", "E"];
+    let mut unit = 0;
+    let mut scale = 1u128;
+    loop {
+        // rounds up first, so a value like 1023.1K is shown as 1.0M
+        let tenths = (size as u128 * 10).div_ceil(scale);
+        if unit > 0 && tenths < 100 {
+            return format!("{}.{}{}", tenths / 10, tenths % 10, units[unit]);
+        }
+        let whole = (size as u128).div_ceil(scale);
+        if whole < 1024 || unit + 1 == units.len() {
+            return format!("{whole}{}", units[unit]);
+        }
+        unit += 1;
+        scale *= 1024;
+    }
+}
+
+/// Indented view of the directories and files, like `tree`.
+pub fn tree_report(root: &Node) -> String {
+    fn add_entries(node: &Node, prefix: &str, report: &mut String) {
+        let entries = node.entries().collect::<Vec<_>>();
+        for (i, entry) in entries.iter().enumerate() {
+            let last = i + 1 == entries.len();
+            let branch = if last { "└── " } else { "├── " };
+            report.push_str(&format!("{prefix}{branch}{} ({})\n", entry.name(), human_size(entry.size())));
+            if let Entry::Dir(dir) = entry {
+                add_entries(dir, &format!("{prefix}{}", if last { "    " } else { "│   " }), report);
+            }
+        }
+    }
+    let mut report = format!("{} ({})\n", root.name, human_size(root.size));
+    add_entries(root, "", &mut report);
+    report
+}
+
+/// All the directories with their path, sorted by decreasing size.
+fn dirs_by_size(root: &Node) -> Vec<(String, u64)> {
+    let mut dirs = Vec::new();
+    root.walk_paths(|path, node| dirs.push((path.to_string(), node.size)));
+    dirs.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    dirs
+}
+
+/// Size and path of each directory, sorted by decreasing size, like `du -h | sort -rh`.
+pub fn du_report(root: &Node) -> String {
+    dirs_by_size(root).iter()
+        .map(|(path, size)| format!("{:>6}  {path}\n", human_size(*size)))
+        .collect()
+}
+
+/// Path and size of the `k` largest directories, the root included.
+pub fn largest_dirs(root: &Node, k: usize) -> Vec<(String, u64)> {
+    let mut dirs = dirs_by_size(root);
+    dirs.truncate(k);
+    dirs
+}
+
+/// Error in a query, with its position in the query text.
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueryError {
+    pub position: usize,
+    pub message: String
+}
+
+impl Display for QueryError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "at position {}: {}", self.position, self.message)
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Str(String),
+    Op(String),
+    Open,
+    Close
+}
+
+/// Splits the query into tokens, with their position.
+fn tokenize(text: &str) -> Result<Vec<(usize, Token)>, QueryError> {
+    let mut tokens = Vec::new();
+    let mut chars = text.char_indices().peekable();
+    while let Some(&(pos, c)) = chars.peek() {
+        match c {
+            _ if c.is_whitespace() => { chars.next(); }
+            '(' => { chars.next(); tokens.push((pos, Token::Open)); }
+            ')' => { chars.next(); tokens.push((pos, Token::Close)); }
+            '"' => {
+                chars.next();
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '"')) => break,
+                        Some((_, c)) => s.push(c),
+                        None => return Err(QueryError { position: pos, message: "unterminated string".to_string() })
+                    }
+                }
+                tokens.push((pos, Token::Str(s)));
+            }
+            '=' | '!' | '<' | '>' | '~' => {
+                chars.next();
+                let mut op = c.to_string();
+                if let Some(&(_, '=')) = chars.peek() {
+                    chars.next();
+                    op.push('=');
+                }
+                if op == "!" {
+                    return Err(QueryError { position: pos, message: "'!' must be followed by '='".to_string() });
+                }
+                tokens.push((pos, Token::Op(op)));
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(&(_, c)) = chars.peek() {
+                    if c.is_whitespace() || "()\"=!<>~".contains(c) {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                tokens.push((pos, Token::Word(word)));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Field { Size, Depth, Name, Path, Type }
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Op { Eq, Ne, Lt, Le, Gt, Ge, Glob }
+
+#[derive(Clone, Debug, PartialEq)]
+enum Value {
+    Number(u64),
+    Text(String)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Cond(Field, Op, Value)
+}
+
+/// Query over the directories and files of a tree, for example:
+/// - `size > 100000 and depth <= 3`
+/// - `type = file and (name ~ "*.log" or name ~ "*.dat")`
+/// - `type = dir largest 5`, or only `largest 5`
+///
+/// The fields are `size`, `depth` (0 for the root), `name`, `path` and `type` (`dir` or `file`).
+/// The numeric fields can be compared with `=`, `!=`, `<`, `<=`, `>`, `>=`, the text fields with
+/// `=`, `!=` and `~` (pattern with `*` and `?`). The conditions can be combined with `and`, `or`,
+/// `not` and parentheses. `largest N` keeps the N biggest entries.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Query {
+    expr: Option<Expr>,
+    largest: Option<usize>
+}
+
+/// Entry matching a query, with its path and depth.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Match<'a> {
+    pub path: String,
+    pub depth: usize,
+    pub entry: Entry<'a>
+}
+
+/// Recursive descent parser of queries.
+struct QueryParser {
+    tokens: Vec<(usize, Token)>,
+    index: usize,
+    end: usize
+}
+
+impl QueryParser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.index).map(|(_, t)| t)
+    }
+
+    fn position(&self) -> usize {
+        self.tokens.get(self.index).map_or(self.end, |(p, _)| *p)
+    }
+
+    fn error<T>(&self, message: &str) -> Result<T, QueryError> {
+        Err(QueryError { position: self.position(), message: message.to_string() })
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.index).map(|(_, t)| t.clone());
+        self.index += 1;
+        token
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Word(w)) if w == keyword)
+    }
+
+    fn query(&mut self) -> Result<Query, QueryError> {
+        let expr = if self.peek().is_none() || self.is_keyword("largest") { None } else { Some(self.or()?) };
+        let mut largest = None;
+        if self.is_keyword("largest") {
+            self.next();
+            match self.peek() {
+                Some(Token::Word(w)) if w.parse::<usize>().is_ok() => largest = w.parse().ok(),
+                _ => return self.error("expected a number after 'largest'")
+            }
+            self.next();
+        }
+        if self.peek().is_some() {
+            return self.error("unexpected token");
+        }
+        Ok(Query { expr, largest })
+    }
+
+    fn or(&mut self) -> Result<Expr, QueryError> {
+        let mut expr = self.and()?;
+        while self.is_keyword("or") {
+            self.next();
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, QueryError> {
+        let mut expr = self.factor()?;
+        while self.is_keyword("and") {
+            self.next();
+            expr = Expr::And(Box::new(expr), Box::new(self.factor()?));
+        }
+        Ok(expr)
+    }
+
+    fn factor(&mut self) -> Result<Expr, QueryError> {
+        if self.is_keyword("not") {
+            self.next();
+            return Ok(Expr::Not(Box::new(self.factor()?)));
+        }
+        if self.peek() == Some(&Token::Open) {
+            self.next();
+            let expr = self.or()?;
+            if self.peek() != Some(&Token::Close) {
+                return self.error("expected ')'");
+            }
+            self.next();
+            return Ok(expr);
+        }
+        self.condition()
+    }
+
+    fn condition(&mut self) -> Result<Expr, QueryError> {
+        let field = match self.peek() {
+            Some(Token::Word(w)) => match w.as_str() {
+                "size" => Field::Size,
+                "depth" => Field::Depth,
+                "name" => Field::Name,
+                "path" => Field::Path,
+                "type" => Field::Type,
+                _ => return self.error(&format!("unknown field '{w}'"))
+            }
+            _ => return self.error("expected a field")
+        };
+        self.next();
+        let op_position = self.position();
+        let op = match self.peek() {
+            Some(Token::Op(op)) => match op.as_str() {
+                "=" => Op::Eq,
+                "!=" => Op::Ne,
+                "<" => Op::Lt,
+                "<=" => Op::Le,
+                ">" => Op::Gt,
+                ">=" => Op::Ge,
+                "~" => Op::Glob,
+                _ => return self.error(&format!("unknown operator '{op}'"))
+            }
+            _ => return self.error("expected an operator")
+        };
+        self.next();
+        let numeric = matches!(field, Field::Size | Field::Depth);
+        let value = match (self.peek(), numeric) {
+            (Some(Token::Word(w)), true) => match w.parse::<u64>() {
+                Ok(n) => Value::Number(n),
+                Err(_) => return self.error(&format!("'{w}' is not a number"))
+            }
+            (Some(Token::Word(w) | Token::Str(w)), false) => Value::Text(w.clone()),
+            _ => return self.error("expected a value")
+        };
+        if numeric && op == Op::Glob || !numeric && !matches!(op, Op::Eq | Op::Ne | Op::Glob) {
+            return Err(QueryError { position: op_position, message: "operator not supported for this field".to_string() });
+        }
+        self.next();
+        Ok(Expr::Cond(field, op, value))
+    }
+}
+
+impl FromStr for Query {
+    type Err = QueryError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = QueryParser { tokens: tokenize(s)?, index: 0, end: s.len() };
+        parser.query()
+    }
+}
+
+/// Matches `text` with a `pattern` where `*` is any sequence of characters and `?` any character.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let (p, t) = (pattern.chars().collect::<Vec<_>>(), text.chars().collect::<Vec<_>>());
+    // positions to come back to after a mismatch: after the last `*`, and in the text
+    let (mut pi, mut ti, mut star) = (0, 0, None);
+    while ti < t.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == t[ti]) {
+            pi += 1;
+            ti += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            star = Some((pi + 1, ti));
+            pi += 1;
+        } else if let Some((sp, st)) = star {
+            pi = sp;
+            ti = st + 1;
+            star = Some((sp, st + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|&c| c == '*')
+}
+
+impl Expr {
+    fn eval(&self, m: &Match) -> bool {
+        match self {
+            Expr::And(a, b) => a.eval(m) && b.eval(m),
+            Expr::Or(a, b) => a.eval(m) || b.eval(m),
+            Expr::Not(a) => !a.eval(m),
+            Expr::Cond(field, op, value) => {
+                let kind = match m.entry { Entry::Dir(_) => "dir", Entry::File(_) => "file" };
+                match (field, value) {
+                    (Field::Size | Field::Depth, Value::Number(n)) => {
+                        let x = if *field == Field::Size { m.entry.size() } else { m.depth as u64 };
+                        match op {
+                            Op::Eq => x == *n,
+                            Op::Ne => x != *n,
+                            Op::Lt => x < *n,
+                            Op::Le => x <= *n,
+                            Op::Gt => x > *n,
+                            Op::Ge => x >= *n,
+                            Op::Glob => false
+                        }
+                    }
+                    (_, Value::Text(s)) => {
+                        let x = match field {
+                            Field::Name => m.entry.name(),
+                            Field::Path => &m.path,
+                            _ => kind
+                        };
+                        match op {
+                            Op::Eq => x == s,
+                            Op::Ne => x != s,
+                            _ => glob_match(s, x)
+                        }
+                    }
+                    _ => false
+                }
+            }
+        }
+    }
+}
+
+impl Query {
+    /// Finds the directories and files of the tree matching the query, in the walking order,
+    /// or by decreasing size with `largest`.
+    pub fn run<'a>(&self, root: &'a Node) -> Vec<Match<'a>> {
+        let mut matches = Vec::new();
+        let mut keep = |m: Match<'a>| {
+            if self.expr.as_ref().is_none_or(|e| e.eval(&m)) {
+                matches.push(m);
+            }
+        };
+        root.walk_paths(|path, node| {
+            let depth = path.split('/').filter(|n| !n.is_empty()).count();
+            keep(Match { path: path.to_string(), depth, entry: Entry::Dir(node) });
+            for file in &node.files {
+                keep(Match { path: join_path(path, &file.name), depth: depth + 1, entry: Entry::File(file) });
+            }
+        });
+        if let Some(n) = self.largest {
+            matches.sort_by_key(|m| std::cmp::Reverse(m.entry.size()));
+            matches.truncate(n);
+        }
+        matches
+    }
+}
+
+/// Sum of all directories with a total size of at most `config.threshold`, including possible overlaps,
+/// or `None` if it doesn't fit in 64 bits.
+fn find_small_dirs(root: &Node, config: &DiskConfig, verbose: bool) -> Option<u64> {
+    if verbose {
+        print!("{}", tree_report(root));
+    }
+    let query = format!("type = dir and size <= {}", config.threshold).parse::<Query>().unwrap();
+    query.run(root).iter().try_fold(0, |total: u64, m| total.checked_add(m.entry.size()))
+}
+
+/// How the cleanup planner chooses the directories to delete.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Strategy {
+    /// the smallest single directory freeing enough space
+    Single,
+    /// the set of non-nested directories freeing enough space with the smallest total size, up to
+    /// the precision given in `plan_cleanup`
+    MinimalSet
+}
+
+/// Directories to delete, with their path and size, and the total size they free.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Cleanup {
+    pub dirs: Vec<(String, u64)>,
+    pub freed: u64
+}
+
+/// Space to free so that `config.required` bytes are available on the disk (0 if they already are),
+/// or `None` if it doesn't fit in 64 bits, which is more than any tree can free.
+fn missing_space(root: &Node, config: &DiskConfig) -> Option<u64> {
+    match config.capacity.checked_sub(config.required) {
+        Some(available) => Some(root.size.saturating_sub(available)),
+        None => root.size.checked_add(config.required - config.capacity)
+    }
+}
+
+/// Plans which directories to delete so that `config.required` bytes are free on the disk.
+/// Returns `None` if even deleting everything isn't enough, or an error if the sizes of the
+/// tree are inconsistent.
+///
+/// With `Strategy::MinimalSet`, the freed sizes are compared by buckets of 1/`PLANNER_BUCKETS` of
+/// the space to free, so the plan may free a little more than the minimum. A tree needing more than
+/// `PLANNER_BUDGET` combinations of sizes gets the plan of `Strategy::Single` instead.
+pub fn plan_cleanup(root: &Node, config: &DiskConfig, strategy: Strategy) -> Result<Option<Cleanup>, SizeError> {
+    root.check_sizes()?;
+    let Some(missing) = missing_space(root, config) else { return Ok(None) };
+    if missing == 0 {
+        return Ok(Some(Cleanup::default()));
+    }
+    let single = || {
+        let mut best: Option<(String, u64)> = None;
+        root.walk_paths(|path, n| {
+            if n.size >= missing && best.as_ref().is_none_or(|b| n.size < b.1) {
+                best = Some((path.to_string(), n.size));
+            }
+        });
+        best.map(|dir| Cleanup { freed: dir.1, dirs: vec![dir] })
+    };
+    match strategy {
+        Strategy::Single => Ok(single()),
+        Strategy::MinimalSet => Ok(Planner::new(root, missing).plan().unwrap_or_else(single))
+    }
+}
+
+/// Number of groups of freed sizes compared by `Strategy::MinimalSet`.
+pub const PLANNER_BUCKETS: u64 = 256;
+
+/// Maximum number of combinations of freed sizes explored by `Strategy::MinimalSet`.
+pub const PLANNER_BUDGET: usize = 1 << 24;
+
+/// Directories deleted by a plan, as a tree sharing the plans of the subtrees.
+enum Plan {
+    Nothing,
+    /// directory at this index in `Planner::dirs`
+    Dir(usize),
+    /// plans of disjoint parts of the tree
+    Both(Vec<Rc<Plan>>)
+}
+
+impl Plan {
+    fn both(a: &Rc<Plan>, b: &Rc<Plan>) -> Rc<Plan> {
+        match (&**a, &**b) {
+            (Plan::Nothing, _) => b.clone(),
+            (_, Plan::Nothing) => a.clone(),
+            _ => Rc::new(Plan::Both(vec![a.clone(), b.clone()]))
+        }
+    }
+}
+
+impl Drop for Plan {
+    /// Drops the nested plans iteratively, as they can be nested as deep as a directory is wide.
+    fn drop(&mut self) {
+        let Plan::Both(plans) = self else { return };
+        let mut stack = std::mem::take(plans);
+        while let Some(plan) = stack.pop() {
+            if let Ok(Plan::Both(plans)) = Rc::try_unwrap(plan).as_mut() {
+                stack.append(plans);
+            }
+        }
+    }
+}
+
+/// Sizes which can be freed in a part of the tree by deleting directories which aren't nested,
+/// with a plan for each of them.
+struct Freeable {
+    /// largest size below `missing` of each bucket of `Planner::width` bytes, with its plan
+    below: BTreeMap<u64, (u64, Rc<Plan>)>,
+    /// smallest size of at least `missing`, as the larger ones can't lead to a better plan
+    enough: Option<(u64, Rc<Plan>)>
+}
+
+impl Freeable {
+    /// Nothing deleted yet.
+    fn nothing() -> Self {
+        Freeable { below: BTreeMap::from([(0, (0, Rc::new(Plan::Nothing)))]), enough: None }
+    }
+
+    /// Keeps `plan` if it frees enough space, but less than the plan kept so far.
+    fn offer(&mut self, freed: u64, plan: Rc<Plan>) {
+        if self.enough.as_ref().is_none_or(|(best, _)| freed < *best) {
+            self.enough = Some((freed, plan));
+        }
+    }
+}
+
+/// Tree knapsack finding a set of non-nested directories with the smallest total size of at
+/// least `missing`. The freed sizes are combined from the leaves up, keeping a single plan per
+/// bucket of sizes below `missing`: the one freeing the most, so it frees enough whenever another
+/// plan of its bucket does. The plan is exact when `missing` is at most `PLANNER_BUCKETS`, and
+/// may otherwise free a little more than the minimum.
+struct Planner<'a> {
+    missing: u64,
+    /// width of the buckets of freed sizes
+    width: u64,
+    /// combinations left to explore
+    budget: usize,
+    /// directories in breadth-first order, with their path and the index of their parent
+    dirs: Vec<(String, &'a Node, Option<usize>)>
+}
+
+impl<'a> Planner<'a> {
+    fn new(root: &'a Node, missing: u64) -> Self {
+        let mut dirs = vec![("/".to_string(), root, None)];
+        let mut index = 0;
+        while index < dirs.len() {
+            let (path, node, _) = &dirs[index];
+            let subdirs = node.dirs.iter().map(|d| (join_path(path, &d.name), d, Some(index))).collect::<Vec<_>>();
+            dirs.extend(subdirs);
+            index += 1;
+        }
+        Planner { missing, width: missing.div_ceil(PLANNER_BUCKETS), budget: PLANNER_BUDGET, dirs }
+    }
+
+    /// Best plan, `Some(None)` if no plan frees enough space, or `None` if the budget runs out.
+    fn plan(mut self) -> Option<Option<Cleanup>> {
+        // the sizes freed in each directory, combined with those of its subdirectories as they
+        // come, which are after it in breadth-first order
+        let mut freeable = (0..self.dirs.len()).map(|_| None).collect::<Vec<Option<Freeable>>>();
+        for index in (1..self.dirs.len()).rev() {
+            let sizes = self.with_dir(index, freeable[index].take());
+            let parent = self.dirs[index].2.unwrap();
+            let siblings = freeable[parent].take().unwrap_or_else(Freeable::nothing);
+            freeable[parent] = Some(self.combine(siblings, &sizes)?);
+        }
+        let sizes = self.with_dir(0, freeable[0].take());
+        Some(sizes.enough.map(|(freed, plan)| self.cleanup(freed, &plan)))
+    }
+
+    /// Keeps the plan made by `plan` if it frees the most in its bucket, or enough but less
+    /// than the plan kept so far.
+    fn keep<F>(&self, sizes: &mut Freeable, freed: u64, plan: F) where F: FnOnce() -> Rc<Plan> {
+        if freed >= self.missing {
+            if sizes.enough.as_ref().is_none_or(|(best, _)| freed < *best) {
+                sizes.enough = Some((freed, plan()));
+            }
+        } else if sizes.below.get(&(freed / self.width)).is_none_or(|(best, _)| freed > *best) {
+            sizes.below.insert(freed / self.width, (freed, plan()));
+        }
+    }
+
+    /// Adds the deletion of directory `index` to the sizes freed in its subdirectories.
+    fn with_dir(&self, index: usize, subdirs: Option<Freeable>) -> Freeable {
+        let mut sizes = subdirs.unwrap_or_else(Freeable::nothing);
+        let size = self.dirs[index].1.size;
+        // deleting the directory is preferred to deleting as much in its subdirectories
+        if size >= self.missing && sizes.enough.as_ref().is_none_or(|(best, _)| size <= *best) {
+            sizes.enough = Some((size, Rc::new(Plan::Dir(index))));
+        } else {
+            self.keep(&mut sizes, size, || Rc::new(Plan::Dir(index)));
+        }
+        sizes
+    }
+
+    /// Adds the sizes freed in `subdir` to those freed in the disjoint part `sizes`, or returns
+    /// `None` once the budget is spent. The sums can't overflow, they're at most the size of the
+    /// root, which has been checked.
+    fn combine(&mut self, mut sizes: Freeable, subdir: &Freeable) -> Option<Freeable> {
+        // both parts can free nothing, so their plans are kept as they are
+        if let Some((freed, plan)) = &subdir.enough {
+            sizes.offer(*freed, plan.clone());
+        }
+        let mut sums = Vec::new();
+        for (x, plan_x) in sizes.below.values() {
+            for (y, plan_y) in subdir.below.values().filter(|(y, _)| *y > 0) {
+                self.budget = self.budget.checked_sub(1)?;
+                sums.push((x + y, plan_x.clone(), plan_y.clone()));
+            }
+        }
+        for (freed, plan_x, plan_y) in sums {
+            self.keep(&mut sizes, freed, || Plan::both(&plan_x, &plan_y));
+        }
+        Some(sizes)
+    }
+
+    /// Directories deleted by `plan`, sorted by decreasing size.
+    fn cleanup(&self, freed: u64, plan: &Rc<Plan>) -> Cleanup {
+        let mut dirs = Vec::new();
+        let mut stack = vec![plan];
+        while let Some(plan) = stack.pop() {
+            match &**plan {
+                Plan::Nothing => {}
+                Plan::Dir(index) => {
+                    let (path, node, _) = &self.dirs[*index];
+                    dirs.push((path.clone(), node.size));
+                }
+                Plan::Both(plans) => stack.extend(plans)
+            }
+        }
+        dirs.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        Cleanup { dirs, freed }
+    }
+}
+
+/// Size of the smallest directory to delete to have `config.required` bytes free on the disk,
+/// or `None` if even deleting everything isn't enough.
+fn select_dir(root: &Node, config: &DiskConfig, verbose: bool) -> Option<u64> {
     let current_occupied = root.size;
-    let current_free = DISK_CAPACITY - current_occupied;
     println!("Occupied:     {current_occupied:9}");
-    println!("Free space:   {current_free:9}");
-    if current_free > UPDATE_SIZE {
+    match config.capacity.checked_sub(current_occupied) {
+        Some(current_free) => println!("Free space:   {current_free:9}"),
+        None => println!("Over capacity:{:9}", current_occupied - config.capacity)
+    }
+    let Some(missing_space) = missing_space(root, config) else {
+        println!("not enough space on the disk");
+        return None;
+    };
+    if missing_space == 0 {
         println!("no need to delete anything");
-        return 0;
+        return Some(0);
     }
-    let missing_space = UPDATE_SIZE - current_free;
     println!("Need to free: {missing_space:9}");
-    let mut min = u32::MAX;
-    root.walk(|n| {
-        if n.size >= missing_space && n.size < min {
-            min = n.size;
+    match plan_cleanup(root, config, Strategy::Single) {
+        Ok(Some(cleanup)) => {
             if verbose {
-                println!("- found:      {:9} occupied by {}", n.size, n.name)
+                for (path, size) in &cleanup.dirs {
+                    println!("- found:      {size:9} occupied by {path}");
+                }
             }
+            Some(cleanup.freed)
+        }
+        Ok(None) => {
+            println!("not enough space on the disk");
+            None
         }
-    });
-    min
+        Err(e) => {
+            println!("invalid tree: {e}");
+            None
+        }
+    }
 }
 
 #[cfg(test)]
@@ -155,16 +1501,468 @@ $ ls
     #[test]
     fn get_small_dirs() {
         let lines = INPUT.lines().map(|s| s.to_string());
-        let size = find_small_dirs(lines, true);
-        println!("size: {size}");
+        let size = find_small_dirs(&parse(lines), &DiskConfig::default(), true);
+        assert_eq!(size, Some(95437));
     }
 
     #[test]
     fn get_smallest_dir() {
         let lines = INPUT.lines().map(|s| s.to_string());
-        let size = select_dir(lines, true);
-        println!("size: {size}");
-        assert_eq!(size, 24933642);
+        let size = select_dir(&parse(lines), &DiskConfig::default(), true);
+        assert_eq!(size, Some(24933642));
+    }
+
+    #[test]
+    fn filesystem() {
+        let lines = INPUT.lines().map(|s| s.to_string());
+        let root = parse(lines);
+        assert_eq!(root.entries().map(|e| e.name().to_string()).collect::<Vec<_>>(), vec!["a", "d", "b.txt", "c.dat"]);
+        assert_eq!(root.get("/a/e/i"), Some(Entry::File(&File { name: "i".to_string(), size: 584 })));
+        assert_eq!(root.get("/a/e").map(|e| e.size()), Some(584));
+        assert_eq!(root.get("a").map(|e| e.size()), Some(94853));
+        assert_eq!(root.get("/").map(|e| e.size()), Some(48381165));
+        assert_eq!(root.get("/a/x"), None);
+        assert_eq!(root.get("/b.txt/x"), None);
+        let mut files = Vec::new();
+        root.walk_files(|path, file| files.push(format!("{path}:{}", file.size)));
+        assert_eq!(files, vec![
+            "/b.txt:14848514", "/c.dat:8504156", "/a/f:29116", "/a/g:2557", "/a/h.lst:62596", "/a/e/i:584",
+            "/d/j:4060174", "/d/d.log:8033020", "/d/d.ext:5626152", "/d/k:7214296"
+        ]);
+    }
+
+    #[test]
+    fn non_linear_navigation() {
+        let input = "\
+$ cd /
+$ ls
+dir a
+1 x
+$ cd a/b
+$ ls
+10 y
+$ cd /a
+$ ls
+dir b
+100 z
+$ cd b/../b
+$ ls
+10 y
+20 w
+$ cd /
+$ cd a
+$ cd ./b
+$ ls
+10 y
+15 w";
+        let (root, warnings) = parse_with_warnings(input.lines().map(|s| s.to_string()));
+        assert_eq!(root.size, 126);
+        assert_eq!(root.dirs.len(), 1);
+        assert_eq!(root.get("/a").map(|e| e.size()), Some(125));
+        assert_eq!(root.get("/a/b").map(|e| e.size()), Some(25));
+        assert_eq!(root.get("/a/b/w").map(|e| e.size()), Some(15));
+        assert_eq!(warnings, vec![
+            Warning::Relisted { line: 13, path: "/a/b".to_string() },
+            Warning::Duplicate { line: 14, path: "/a/b/y".to_string() },
+            Warning::Relisted { line: 19, path: "/a/b".to_string() },
+            Warning::Duplicate { line: 20, path: "/a/b/y".to_string() },
+            Warning::Resized { line: 21, path: "/a/b/w".to_string(), old: 20, new: 15 },
+        ]);
+        assert_eq!(resolve_path(&["a".to_string()], "../b/./c/.."), vec!["b".to_string()]);
+        assert_eq!(resolve_path(&["a".to_string()], "/"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn reports() {
+        let lines = INPUT.lines().map(|s| s.to_string());
+        let root = parse(lines);
+        assert_eq!(tree_report(&root), "\
+/ (47M)
+├── a (93K)
+│   ├── e (584B)
+│   │   └── i (584B)
+│   ├── f (29K)
+│   ├── g (2.5K)
+│   └── h.lst (62K)
+├── d (24M)
+│   ├── j (3.9M)
+│   ├── d.log (7.7M)
+│   ├── d.ext (5.4M)
+│   └── k (6.9M)
+├── b.txt (15M)
+└── c.dat (8.2M)
+");
+        assert_eq!(du_report(&root), "   47M  /\n   24M  /d\n   93K  /a\n  584B  /a/e\n");
+        assert_eq!(largest_dirs(&root, 2), vec![("/".to_string(), 48381165), ("/d".to_string(), 24933642)]);
+        assert_eq!(human_size(0), "0B");
+        assert_eq!(human_size(1023), "1023B");
+        assert_eq!(human_size(1024), "1.0K");
+        assert_eq!(human_size(1025), "1.1K");
+        assert_eq!(human_size(10137), "9.9K");
+        assert_eq!(human_size(10138), "10K");
+        assert_eq!(human_size(1_047_552), "1023K");
+        assert_eq!(human_size(1_047_553), "1.0M");
+        assert_eq!(human_size(1_048_575), "1.0M");
+        assert_eq!(human_size(48_381_165), "47M");
+        assert_eq!(human_size(1_073_741_823), "1.0G");
+        assert_eq!(human_size(u32::MAX as u64), "4.0G");
+        assert_eq!(human_size(u64::MAX), "16E");
+    }
+
+    fn disk(capacity: u64, required: u64) -> DiskConfig {
+        DiskConfig { capacity, required, ..DiskConfig::default() }
+    }
+
+    #[test]
+    fn disk_config() {
+        assert_eq!("".parse(), Ok(DiskConfig::default()));
+        let config = "capacity=80_000_000, threshold=1000".parse::<DiskConfig>().unwrap();
+        assert_eq!(config, DiskConfig { capacity: 80_000_000, required: 30_000_000, threshold: 1000 });
+        assert_eq!("size=1".parse::<DiskConfig>(), Err(DiskConfigError("size=1".to_string())));
+        assert_eq!("capacity=-1".parse::<DiskConfig>().unwrap_err().to_string(), "invalid disk parameter 'capacity=-1'");
+        assert_eq!("capacity".parse::<DiskConfig>(), Err(DiskConfigError("capacity".to_string())));
+
+        // the disk is already over capacity, and sizes beyond 4 GB are kept
+        let lines = INPUT.lines().map(|s| s.to_string());
+        let mut root = parse(lines);
+        assert_eq!(select_dir(&root, &disk(40_000_000, 1), false), Some(24933642));
+        assert_eq!(select_dir(&root, &disk(10, 30_000_000), false), None);
+        root.insert_file(&["d".to_string()], File { name: "big.iso".to_string(), size: 5 << 30 }).unwrap();
+        assert_eq!(root.size, 48381165 + (5 << 30));
+        assert_eq!(find_small_dirs(&root, &disk(0, 0), false), Some(94853 + 584));
+    }
+
+    #[test]
+    fn size_overflow() {
+        let max = u64::MAX;
+        let mut root = Node::new("/".to_string());
+        root.insert_file(&["a".to_string()], File { name: "x".to_string(), size: max - 1 }).unwrap();
+        let copy = root.clone();
+        let file = File { name: "y".to_string(), size: 2 };
+        assert_eq!(root.insert_file(&["b".to_string()], file), Err(SizeError::Overflow { path: "/".to_string() }));
+        assert_eq!(root, copy);
+        // replacing a file only counts the difference
+        let file = File { name: "x".to_string(), size: max };
+        assert_eq!(root.insert_file(&["a".to_string()], file).map(|old| old.map(|f| f.size)), Ok(Some(max - 1)));
+        assert_eq!(root.size, max);
+        let cleanup = plan_cleanup(&root, &disk(max, max), Strategy::MinimalSet).unwrap().unwrap();
+        assert_eq!(cleanup.dirs, vec![("/".to_string(), max)]);
+        assert_eq!(plan_cleanup(&root, &disk(0, 1), Strategy::MinimalSet), Ok(None));
+
+        let lines = ["$ cd /", "$ ls", &format!("{max} a"), "1 b"].map(|s| s.to_string());
+        let (root, warnings) = parse_with_warnings(lines.into_iter());
+        assert_eq!(warnings, vec![Warning::Overflow { line: 4, path: "/b".to_string() }]);
+        assert_eq!(root.size, max);
+
+        // the sizes of a tree built by hand are checked before planning
+        let mut root = Node::new("/".to_string());
+        root.dirs = vec![Node { size: max, ..Node::new("a".to_string()) }, Node { size: 1, ..Node::new("b".to_string()) }];
+        root.size = max;
+        let error = plan_cleanup(&root, &disk(0, 1), Strategy::MinimalSet);
+        assert_eq!(error, Err(SizeError::Overflow { path: "/".to_string() }));
+        let json = root.to_json().unwrap();
+        assert!(matches!(Node::from_json(&json), Err(ImportError::Size(SizeError::Overflow { .. }))));
+        root.dirs[0].size = 3;
+        assert!(matches!(Node::from_json(&root.to_json().unwrap()), Err(ImportError::Size(SizeError::Mismatch { .. }))));
+
+        // the binary form doesn't store the sizes of the directories, they're added when reading
+        root.files = vec![File { name: "x".to_string(), size: max }, File { name: "y".to_string(), size: 2 }];
+        assert_eq!(Node::from_bytes(&root.to_bytes().unwrap()).unwrap_err().to_string(), "size of / overflows");
+    }
+
+    #[test]
+    fn cleanup() {
+        let lines = INPUT.lines().map(|s| s.to_string());
+        let root = parse(lines);
+        let single = plan_cleanup(&root, &disk(70_000_000, 30_000_000), Strategy::Single).unwrap();
+        assert_eq!(single, Some(Cleanup { dirs: vec![("/d".to_string(), 24933642)], freed: 24933642 }));
+        let set = plan_cleanup(&root, &disk(70_000_000, 30_000_000), Strategy::MinimalSet).unwrap();
+        assert_eq!(set, single);
+        // /a (94853) isn't enough, and /a/e (584) is nested in it
+        let set = plan_cleanup(&root, &disk(48_381_165, 95_000), Strategy::MinimalSet).unwrap().unwrap();
+        assert_eq!(set.freed, 24933642);
+        let set = plan_cleanup(&root, &disk(48_381_165, 24_933_642 + 94_853), Strategy::MinimalSet).unwrap().unwrap();
+        assert_eq!(set.dirs, vec![("/d".to_string(), 24933642), ("/a".to_string(), 94853)]);
+        assert_eq!(plan_cleanup(&root, &disk(70_000_000, 1), Strategy::MinimalSet), Ok(Some(Cleanup::default())));
+        assert_eq!(plan_cleanup(&root, &disk(10, 1), Strategy::Single).unwrap().map(|c| c.freed), Some(48381165));
+        assert_eq!(plan_cleanup(&root, &disk(10, 100), Strategy::MinimalSet), Ok(None));
+        assert_eq!(plan_cleanup(&root, &disk(0, u64::MAX), Strategy::MinimalSet), Ok(None));
+    }
+
+    #[test]
+    fn cleanup_wide() {
+        let wide = |sizes: &[u64]| {
+            let mut root = Node::new("/".to_string());
+            for (i, &size) in sizes.iter().enumerate() {
+                root.insert_file(&[format!("d{i}")], File { name: "f".to_string(), size }).unwrap();
+            }
+            root
+        };
+        // 60 directories of 1 to 60 bytes, half of the 1830 bytes to free
+        let root = wide(&(1..=60).collect::<Vec<_>>());
+        let set = plan_cleanup(&root, &disk(1830, 915), Strategy::MinimalSet).unwrap().unwrap();
+        assert_eq!(set.freed, 915);
+        assert_eq!(set.dirs.iter().map(|d| d.1).sum::<u64>(), 915);
+        assert_eq!(set.dirs[..3], [("/d59".to_string(), 60), ("/d58".to_string(), 59), ("/d57".to_string(), 58)]);
+
+        // all the 2^60 sets have a different size, only one per bucket is kept
+        let root = wide(&(0..60).map(|i| 1 << i).collect::<Vec<_>>());
+        let missing = (1 << 59) - 1;
+        let set = plan_cleanup(&root, &disk(root.size, missing), Strategy::MinimalSet).unwrap().unwrap();
+        assert!(set.freed >= missing && set.freed <= 1 << 59);
+        // the planner gives up once the budget is spent, plan_cleanup then uses Strategy::Single
+        assert!(Planner { budget: 1000, ..Planner::new(&root, missing) }.plan().is_none());
+
+        // thousands of directories of a few different sizes
+        let root = wide(&(0..5000).map(|i| 1000 + i % 7 * 100).collect::<Vec<_>>());
+        let set = plan_cleanup(&root, &disk(root.size, 12_345), Strategy::MinimalSet).unwrap().unwrap();
+        assert_eq!(set.freed, 12_400);
+    }
+
+    #[test]
+    fn cleanup_today() {
+        let root = parse(get_file_lines("data/07_data.txt"));
+        let single = plan_cleanup(&root, &DiskConfig::default(), Strategy::Single).unwrap().unwrap();
+        let set = plan_cleanup(&root, &DiskConfig::default(), Strategy::MinimalSet).unwrap().unwrap();
+        assert!(set.freed <= single.freed);
+        assert!(Some(set.freed) >= missing_space(&root, &DiskConfig::default()));
+        println!("single: {single:?}\nset: {set:?}");
+    }
+
+    #[test]
+    fn queries() {
+        let lines = INPUT.lines().map(|s| s.to_string());
+        let root = parse(lines);
+        let paths = |query: &str| query.parse::<Query>().unwrap().run(&root).iter().map(|m| m.path.clone()).collect::<Vec<_>>();
+        assert_eq!(paths("size > 100000 and depth <= 1 and type = dir"), vec!["/", "/d"]);
+        assert_eq!(paths("name ~ \"*.l?g\" or name ~ *.lst"), vec!["/a/h.lst", "/d/d.log"]);
+        assert_eq!(paths("type=file and not (depth != 2 or size >= 10000)"), vec!["/a/g"]);
+        assert_eq!(paths("path ~ /a/* and type = dir"), vec!["/a/e"]);
+        assert_eq!(paths("largest 3"), vec!["/", "/d", "/b.txt"]);
+        assert_eq!(paths("type = dir largest 2"), vec!["/", "/d"]);
+        assert_eq!(paths(""), paths("size >= 0"));
+        let error = |query: &str| query.parse::<Query>().unwrap_err().to_string();
+        assert_eq!(error("size > big"), "at position 7: 'big' is not a number");
+        assert_eq!(error("size ~ 12"), "at position 5: operator not supported for this field");
+        assert_eq!(error("colour = red"), "at position 0: unknown field 'colour'");
+        assert_eq!(error("(size > 1"), "at position 9: expected ')'");
+        assert_eq!(error("largest"), "at position 7: expected a number after 'largest'");
+        assert_eq!(error("name = \"x"), "at position 7: unterminated string");
+        assert_eq!(error("size > 1 size"), "at position 9: unexpected token");
+        assert!(glob_match("*", ""));
+        assert!(glob_match("a*b*c", "aXbYbc"));
+        assert!(!glob_match("a*b?", "ab"));
+    }
+
+    #[test]
+    fn scan() {
+        let dir = std::env::temp_dir().join(format!("advent2022_day07_{}", std::process::id()));
+        fs::create_dir_all(dir.join("a/e")).unwrap();
+        fs::create_dir_all(dir.join("d")).unwrap();
+        fs::write(dir.join("b.txt"), vec![0; 1500]).unwrap();
+        fs::write(dir.join("a/f"), vec![0; 300]).unwrap();
+        fs::write(dir.join("a/e/i"), vec![0; 20]).unwrap();
+        let root = scan_dir(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+        let root = root.unwrap();
+        let mut dirs = Vec::new();
+        root.walk_paths(|path, node| dirs.push(format!("{path}:{}", node.size)));
+        assert_eq!(dirs, vec!["/:1820", "/a:320", "/a/e:20", "/d:0"]);
+        assert_eq!(root.get("/b.txt").map(|e| e.size()), Some(1500));
+        assert_eq!(find_small_dirs(&root, &DiskConfig::default(), false), Some(2160));
+        let cleanup = plan_cleanup(&root, &disk(2000, 500), Strategy::MinimalSet).unwrap().unwrap();
+        assert_eq!(cleanup.dirs, vec![("/a".to_string(), 320)]);
+        assert!(scan_dir(dir.join("missing")).is_err());
+    }
+
+    #[test]
+    fn incremental_updates() {
+        let lines = INPUT.lines().map(|s| s.to_string());
+        let before = parse(lines);
+        let mut after = before.clone();
+        let update = "\
+$ cd /a
+$ rm e
+$ rm f
+$ rm /a/x
+$ ls
+1000 f
+dir n
+$ cd n
+$ ls
+50 o
+$ cd /d
+$ rm ../c.dat
+$ rm /";
+        let warnings = after.apply(update.lines().map(|s| s.to_string()));
+        assert_eq!(warnings, vec![
+            Warning::NotRemoved { line: 4, path: "/a/x".to_string() },
+            Warning::NotRemoved { line: 13, path: "/".to_string() }
+        ]);
+        // same sizes as a tree rebuilt from scratch
+        let rebuilt = parse(transcript(&after, Order::DepthFirst).into_iter());
+        assert_eq!(after, rebuilt);
+        assert_eq!(after.get("/a").map(|e| e.size()), Some(94853 - 584 - 29116 + 1000 + 50));
+        assert_eq!(after.get("/a/e"), None);
+        assert_eq!(after.remove(&["d".to_string()]), Some(24933642));
+        assert_eq!(after.remove(&[]), None);
+
+        let changes = diff(&before, &after);
+        assert_eq!(changes.iter().map(|c| c.to_string()).collect::<Vec<_>>(), vec![
+            "-33466448 / (48381165 -> 14914717)",
+            "-28650 /a (94853 -> 66203)",
+            "-584 /a/e (584 -> 0)",
+            "+50 /a/n (0 -> 50)",
+            "-24933642 /d (24933642 -> 0)"
+        ]);
+        assert!(changes[3].grown());
+
+        // malformed file lines are reported instead of stopping the update
+        let update = ["$ cd /a", "123", "12x y", "99999999999999999999 z", "123 ", "7 ok"].map(|s| s.to_string());
+        let warnings = after.apply(update.into_iter());
+        let bad = |line: usize, text: &str| Warning::BadLine { line, text: text.to_string() };
+        assert_eq!(warnings, vec![bad(2, "123"), bad(3, "12x y"), bad(4, "99999999999999999999 z"), bad(5, "123 ")]);
+        assert_eq!(after.get("/a/ok").map(|e| e.size()), Some(7));
+        assert!(diff(&before, &before).is_empty());
+    }
+
+    #[test]
+    fn deep_transcript() {
+        // a directory is ignored with its content past the depth limit
+        let cd = |depth: usize| format!("$ cd {}", vec!["a"; depth].join("/"));
+        let lines = ["$ cd /".to_string(), cd(100_000), "$ ls".to_string(), "1 f".to_string()];
+        let (root, warnings) = parse_with_warnings(lines.into_iter());
+        let path = format!("/{}", vec!["a"; 100_000].join("/"));
+        assert_eq!(warnings, vec![Warning::TooDeep { line: 2, path }]);
+        assert_eq!(root.size, 0);
+
+        let lines = [cd(MAX_DEPTH), "$ ls".to_string(), "dir b".to_string(), "1 f".to_string(), "$ cd b".to_string()];
+        let (mut root, warnings) = parse_with_warnings(lines.into_iter());
+        let path = format!("/{}", vec!["a"; MAX_DEPTH].join("/"));
+        assert_eq!(warnings, vec![
+            Warning::TooDeep { line: 3, path: format!("{path}/b") },
+            Warning::TooDeep { line: 5, path: format!("{path}/b") }
+        ]);
+        assert_eq!(root.size, 1);
+        assert_eq!(root.get(&format!("{path}/f")).map(|e| e.size()), Some(1));
+        assert!(root.get(&format!("{path}/b")).is_none());
+        let mut path = vec!["a".to_string(); MAX_DEPTH];
+        path.push("f".to_string());
+        assert_eq!(root.remove(&path), Some(1));
+        assert_eq!(root.size, 0);
+        assert_eq!(root.check_sizes(), Ok(()));
+    }
+
+    #[test]
+    fn export() {
+        let mut root = Node::new("/".to_string());
+        root.insert_file(&["a".to_string()], File { name: "x\"y".to_string(), size: 12 }).unwrap();
+        root.mkdir(&["b".to_string()]);
+        root.insert_file(&[], File { name: "z".to_string(), size: 300 }).unwrap();
+        assert_eq!(root.to_json().unwrap(), r#"{
+  "name": "/",
+  "size": 312,
+  "dirs": [
+    {
+      "name": "a",
+      "size": 12,
+      "dirs": [],
+      "files": [
+        {
+          "name": "x\"y",
+          "size": 12
+        }
+      ]
+    },
+    {
+      "name": "b",
+      "size": 0,
+      "dirs": [],
+      "files": []
+    }
+  ],
+  "files": [
+    {
+      "name": "z",
+      "size": 300
+    }
+  ]
+}"#);
+        assert_eq!(root.to_bytes().unwrap(), b"D7T\x01\x01/\x01\x01z\xac\x02\x02\x01a\x01\x03x\"y\x0c\x00\x01b\x00\x00");
+
+        for root in [root, parse(INPUT.lines().map(|s| s.to_string())), parse(get_file_lines("data/07_data.txt"))] {
+            assert_eq!(Node::from_json(&root.to_json().unwrap()).unwrap(), root);
+            assert_eq!(Node::from_bytes(&root.to_bytes().unwrap()).unwrap(), root);
+        }
+
+        let json = r#"{"name": "/", "size": 5, "dirs": [{"name": "a", "size": 1, "dirs": [], "files": []}], "files": []}"#;
+        let error = Node::from_json(json).unwrap_err();
+        assert_eq!(error.to_string(), "size of / is 5, but its content has a size of 1");
+        assert!(matches!(Node::from_json(&json.replace("5,", "1,")), Err(ImportError::Size(SizeError::Mismatch { path, .. })) if path == "/a"));
+        assert!(matches!(Node::from_json(r#"{"name": "/", "size": 0}"#), Err(ImportError::Json(_))));
+        assert!(matches!(Node::from_bytes(b"D7T\x02\x01/\x00\x00"), Err(ImportError::Binary(0))));
+        assert!(matches!(Node::from_bytes(b"D7T\x01\x01/\x01\x01z"), Err(ImportError::Binary(9))));
+        assert!(matches!(Node::from_bytes(b"D7T\x01\x01/\x00\x00\x00"), Err(ImportError::Binary(8))));
+        assert!(matches!(Node::from_bytes(b"D7T\x01\x05/"), Err(ImportError::Binary(4))));
+    }
+
+    #[test]
+    fn export_deep_tree() {
+        let deep = |depth: usize| {
+            let mut root = Node::new("/".to_string());
+            let path = vec!["d".to_string(); depth];
+            root.insert_file(&path, File { name: "f".to_string(), size: 1 }).unwrap();
+            root
+        };
+        // the same tree as compact JSON, as the indentation of to_json grows with the depth
+        let deep_json = |depth: usize| {
+            let mut json = r#"{"name": "/", "size": 1, "files": [], "dirs": ["#.to_string();
+            json += &r#"{"name": "d", "size": 1, "files": [], "dirs": ["#.repeat(depth - 1);
+            json += r#"{"name": "d", "size": 1, "files": [{"name": "f", "size": 1}], "dirs": []}"#;
+            json + &"]}".repeat(depth)
+        };
+        let root = deep(100);
+        assert_eq!(Node::from_json(&root.to_json().unwrap()).unwrap(), root);
+        for depth in [100, MAX_DEPTH] {
+            let root = deep(depth);
+            assert_eq!(Node::from_json(&deep_json(depth)).unwrap(), root);
+            assert_eq!(Node::from_bytes(&root.to_bytes().unwrap()).unwrap(), root);
+        }
+        let error = Node::from_json(&deep_json(MAX_DEPTH + 1)).unwrap_err();
+        assert_eq!(error.to_string(), "invalid JSON tree: tree deeper than 4096 levels");
+        // a deeper tree is rejected on export too, instead of producing what can't be read back
+        let root = deep(MAX_DEPTH + 1);
+        let path = "/d".repeat(MAX_DEPTH + 1);
+        assert_eq!(root.to_json(), Err(DepthError { path: path.clone() }));
+        assert_eq!(root.to_bytes().unwrap_err().to_string(), format!("{path} is more than 4096 levels deep"));
+        assert_eq!(deep(MAX_DEPTH).check_depth(), Ok(()));
+
+        // crafted input nested far deeper is rejected without overflowing the stack
+        let levels = 1_000_000;
+        let json = r#"{"name": "d", "size": 0, "files": [], "dirs": ["#.repeat(levels);
+        assert!(matches!(Node::from_json(&json), Err(ImportError::Json(_))));
+        let mut bytes = BINARY_MAGIC.to_vec();
+        bytes.extend(b"\x01d\x00\x01".repeat(levels));
+        assert_eq!(Node::from_bytes(&bytes).unwrap_err().to_string(), "invalid binary tree at offset 16392");
+    }
+
+    #[test]
+    fn generate_transcript() {
+        let lines = INPUT.lines().map(|s| s.to_string());
+        let root = parse(lines);
+        let lines = transcript(&root, Order::DepthFirst);
+        assert_eq!(lines[..9], ["$ cd /", "$ ls", "dir a", "dir d", "14848514 b.txt", "8504156 c.dat", "$ cd a", "$ ls", "dir e"]);
+        assert_eq!(lines.last().unwrap(), "7214296 k");
+        let (copy, warnings) = parse_with_warnings(lines.into_iter());
+        assert_eq!(copy, root);
+        assert!(warnings.is_empty());
+        let lines = transcript(&root, Order::BreadthFirst);
+        assert_eq!(lines.iter().filter(|l| l.starts_with("$ cd")).collect::<Vec<_>>(), ["$ cd /", "$ cd /a", "$ cd /d", "$ cd /a/e"]);
+        let (copy, warnings) = parse_with_warnings(lines.into_iter());
+        assert_eq!(copy, root);
+        assert!(warnings.is_empty());
+        let root = parse(get_file_lines("data/07_data.txt"));
+        assert_eq!(parse(transcript(&root, Order::DepthFirst).into_iter()), root);
     }
 
     #[test]
@@ -1,7 +1,6 @@
-use crate::get_file_lines;
+use crate::{get_file_lines, Dir, Grid, Pos};
 
 #[allow(dead_code)]
-
 pub fn day08() {
     let trees = visible_trees(get_file_lines("data/08_data.txt"), false);
     println!("1) Number of visible trees: {trees}");
@@ -9,124 +8,118 @@ pub fn day08() {
     println!("2) Scenic score: {score}");
 }
 
-/// Parses the input lines and returns the trees.
-fn parse_trees<E>(lines: E) -> Vec<Vec<(u8, bool)>>
+/// Parses the input lines and returns the heights of the trees.
+pub fn parse_trees<E>(lines: E) -> Grid<u8>
     where E: Iterator<Item = String>
 {
-    lines.map(|line|
-        line.into_bytes().into_iter().map(|t| (t, false)).collect::<Vec<_>>()
-    ).collect::<Vec<_>>()
+    Grid::parse(lines, |c| c.to_digit(10).map(|h| h as u8)).unwrap()
 }
 
-/// Number of visible trees from outside.
-fn visible_trees<E>(lines: E, verbose: bool) -> usize
-    where E: Iterator<Item = String>
-{
-    let mut visible: usize = 0;
-    let mut trees = parse_trees(lines);
-    let h = trees.len();
-    let w = trees[0].len();
-    let mut hmax: Vec<u8> = vec![0; w];
-    for hline in &mut trees {
-        for i in 0..w {
-            if hline[i].0 >= hmax[i] {
-                hline[i].1 = true;
-                hmax[i] = hline[i].0 + 1;
-                visible += 1;
-            }
-        }
+/// What can be seen from a tree, and from where it can be seen. The arrays are indexed
+/// by direction, in the order of `Dir::ALL`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TreeView {
+    pub height: u8,
+    /// the tree is visible from outside the forest in this direction
+    pub visible: [bool; 4],
+    /// viewing distance in this direction
+    pub distances: [usize; 4]
+}
+
+impl TreeView {
+    pub fn visible_from(&self, dir: Dir) -> bool {
+        self.visible[dir as usize]
     }
-    let mut hmax: Vec<u8> = vec![0; w];
-    for hline in &mut trees.iter_mut().rev() {
-        for i in 0..w {
-            if hline[i].0 >= hmax[i] {
-                if !hline[i].1 {
-                    hline[i].1 = true;
-                    visible += 1;
-                }
-                hmax[i] = hline[i].0 + 1;
-            }
-        }
+
+    pub fn distance(&self, dir: Dir) -> usize {
+        self.distances[dir as usize]
     }
-    let mut vmax_l: Vec<u8> = vec![0; h];
-    let mut vmax_r: Vec<u8> = vec![0; h];
-    for x in 0..w {
-        for y in 0..h {
-            let tree = &mut trees[y][x];
-            if tree.0 >= vmax_l[y] {
-                if !tree.1 {
-                    tree.1 = true;
-                    visible += 1;
-                }
-                vmax_l[y] = tree.0 + 1;
-            }
-            let tree = &mut trees[y][w - x - 1];
-            if tree.0 >= vmax_r[y] {
-                if !tree.1 {
-                    tree.1 = true;
-                    visible += 1;
+
+    /// Visible from outside the forest in at least one direction.
+    pub fn is_visible(&self) -> bool {
+        self.visible.contains(&true)
+    }
+
+    pub fn scenic_score(&self) -> usize {
+        self.distances.iter().product()
+    }
+}
+
+/// Visibility and viewing distances of each tree of the forest.
+pub fn tree_views(trees: &Grid<u8>) -> Grid<TreeView> {
+    trees.map(|pos, &height| TreeView {
+        height,
+        visible: Dir::ALL.map(|dir| trees.ray(pos, dir).all(|(_, &h)| h < height)),
+        distances: Dir::ALL.map(|dir| distance(trees, pos, dir))
+    })
+}
+
+/// Draws each tree as a block of 3x3 characters, with its height in the middle and an arrow
+/// on each side from which it's visible:
+/// ```text
+///  ^
+/// <5>
+///  v
+/// ```
+pub fn render_visibility(views: &Grid<TreeView>) -> String {
+    let mut text = String::new();
+    for row in views.rows() {
+        for line in 0..3 {
+            for view in row {
+                let [up, down, left, right] = view.visible;
+                let mark = |visible, c| if visible { c } else { ' ' };
+                match line {
+                    0 => text.extend([' ', mark(up, '^'), ' ']),
+                    1 => text.push_str(&format!("{}{}{}", mark(left, '<'), view.height, mark(right, '>'))),
+                    _ => text.extend([' ', mark(down, 'v'), ' '])
                 }
-                vmax_r[y] = tree.0 + 1;
             }
+            text.push('\n');
         }
     }
+    text
+}
+
+/// Number of visible trees from outside.
+fn visible_trees<E>(lines: E, verbose: bool) -> usize
+    where E: Iterator<Item = String>
+{
+    let trees = parse_trees(lines);
+    let views = tree_views(&trees);
     if verbose {
-        print_forest(&trees, "finished:");
+        print!("{}", render_visibility(&views));
     }
-    visible
+    views.iter().filter(|(_, v)| v.is_visible()).count()
 }
 
 /// Shows the forest and the tagged trees (debug feature).
-fn print_forest(trees: &Vec<Vec<(u8, bool)>>, x1: &str) {
+fn print_forest(trees: &Grid<u8>, tagged: &Grid<bool>, x1: &str) {
     println!("{x1}");
-    for hline in trees {
-        for (h, done) in hline {
-            let c = *h as char;
-            if *done {
-                print!("[{c}]");
-            } else {
-                print!(" {c} ");
-            }
-        }
-        println!();
-    }
+    print!("{}", trees.map(|pos, h| if tagged[pos] { format!("[{h}]") } else { format!(" {h} ") }));
 }
 
 /// Best scenic score.
 fn scenic_score<E>(lines: E, verbose: bool) -> usize
     where E: Iterator<Item = String>
 {
-    let mut score = 0;
-    let mut coord = (0, 0);
-    let mut trees = parse_trees(lines);
-    let h = trees.len();
-    let w = trees[0].len();
-    for y in 1..h-1 {
-        for x in 1..w-1 {
-            let tree = trees[y][x];
-            let d = [(-1, 0), (1, 0), (0, -1), (0, 1)].iter()
-                .map(|delta| distance(&trees, w, h, tree.0, (x, y), *delta))
-                .product();
-            if d > score {
-                coord = (x, y);
-                score = d;
-            }
-        }
-    }
+    let trees = parse_trees(lines);
+    let (coord, score) = tree_views(&trees).iter()
+        .map(|(pos, view)| (pos, view.scenic_score()))
+        .fold(((0, 0), 0), |best, (pos, d)| if d > best.1 { (pos, d) } else { best });
     if verbose {
-        trees[coord.1][coord.0].1 = true;
-        print_forest(&trees, &format!("best score: {score}"));
+        print_forest(&trees, &trees.map(|pos, _| pos == coord), &format!("best score: {score}"));
     }
     score
 }
 
-/// Distance to a higher tree at the `pos` position in the `dpos` direction.
-fn distance(trees: &Vec<Vec<(u8, bool)>>, w: usize, h: usize, height: u8, mut pos: (usize, usize), dpos: (isize, isize)) -> usize {
+/// Viewing distance from the tree at `pos` in the `dir` direction, up to the first tree at
+/// least as high or the edge.
+fn distance(trees: &Grid<u8>, pos: Pos, dir: Dir) -> usize {
+    let height = trees[pos];
     let mut d = 0;
-    while 0 < pos.0 && pos.0 < w - 1 && 0 < pos.1 && pos.1 < h - 1 {
+    for (_, &h) in trees.ray(pos, dir) {
         d += 1;
-        pos = ((pos.0 as isize + dpos.0) as usize, (pos.1 as isize + dpos.1) as usize);
-        if trees[pos.1][pos.0].0 >= height { break }
+        if h >= height { break }
     }
     d
 }
@@ -156,6 +149,26 @@ mod tests {
         assert_eq!(score, 8);
     }
 
+    #[test]
+    fn views() {
+        let views = tree_views(&parse_trees(INPUT.lines().map(|s| s.to_string())));
+        let view = views[(2, 3)];
+        assert_eq!(view, TreeView { height: 5, visible: [false, true, true, false], distances: [2, 1, 2, 2] });
+        assert!(view.visible_from(Dir::Left) && view.is_visible());
+        assert_eq!((view.distance(Dir::Up), view.scenic_score()), (2, 8));
+        assert_eq!(views[(0, 0)].distances, [0, 2, 0, 2]);
+        assert!(!views[(3, 1)].is_visible());
+        let text = render_visibility(&views);
+        assert_eq!(text.lines().take(6).collect::<Vec<_>>(), [
+            " ^  ^  ^  ^  ^ ",
+            "<3  0  3 <7> 3>",
+            "               ",
+            "    ^  ^       ",
+            "<2 <5  5> 1  2>",
+            "               "
+        ]);
+    }
+
     #[test]
     fn today() {
         day08();
@@ -7,12 +7,266 @@ pub mod day06;
 pub mod day07;
 pub mod day08;
 
+use std::fmt::{Display, Formatter};
 use std::fs::File;
 use std::io;
 use std::io::{BufRead, BufReader, Lines};
 use std::iter::Map;
+use std::ops::{Index, IndexMut};
 
-pub fn get_file_lines(name: &str) -> Map<Lines<BufReader<File>>, fn(io::Result<String>) -> String> {
+pub type FileLines = Map<Lines<BufReader<File>>, fn(io::Result<String>) -> String>;
+
+pub fn get_file_lines(name: &str) -> FileLines {
     let file = File::open(name).unwrap();
     BufReader::new(file).lines().map(|line| line.unwrap())
 }
+
+/// Position in a grid, as `(x, y)`: the column, then the row from the top.
+pub type Pos = (usize, usize);
+
+/// Direction on a grid.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Dir {
+    Up,
+    Down,
+    Left,
+    Right
+}
+
+impl Dir {
+    /// All the directions, in the order of declaration, so `dir as usize` is the index of `dir`.
+    pub const ALL: [Dir; 4] = [Dir::Up, Dir::Down, Dir::Left, Dir::Right];
+
+    /// Offset of one step in this direction, as `(dx, dy)`.
+    pub fn delta(self) -> (isize, isize) {
+        match self {
+            Dir::Up => (0, -1),
+            Dir::Down => (0, 1),
+            Dir::Left => (-1, 0),
+            Dir::Right => (1, 0)
+        }
+    }
+}
+
+/// Error when parsing a grid, with the line number (starting at 1).
+#[derive(Clone, Debug, PartialEq)]
+pub enum GridError {
+    /// no line, or an empty first line
+    Empty,
+    /// line whose width differs from the first one
+    Ragged { line: usize, width: usize, expected: usize },
+    /// character which isn't a valid cell, at the given column (starting at 1)
+    BadCell { line: usize, column: usize, c: char }
+}
+
+impl Display for GridError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GridError::Empty => write!(f, "empty grid"),
+            GridError::Ragged { line, width, expected } =>
+                write!(f, "line {line}: {width} cells, expected {expected}"),
+            GridError::BadCell { line, column, c } => write!(f, "line {line}, column {column}: invalid cell '{c}'")
+        }
+    }
+}
+
+impl std::error::Error for GridError {}
+
+/// Rectangular grid of cells, stored row by row.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Grid<T> {
+    width: usize,
+    height: usize,
+    cells: Vec<T>
+}
+
+impl<T> Grid<T> {
+    /// Grid of `width` x `height` cells set to `value`.
+    pub fn new(width: usize, height: usize, value: T) -> Self where T: Clone {
+        Grid { width, height, cells: vec![value; width * height] }
+    }
+
+    /// Parses a grid with one row per line, converting each character with `cell`, which
+    /// returns `None` for an invalid character. All the lines must have the same width.
+    pub fn parse<E, F>(lines: E, mut cell: F) -> Result<Self, GridError>
+        where E: Iterator<Item = String>,
+              F: FnMut(char) -> Option<T>
+    {
+        let mut grid = Grid { width: 0, height: 0, cells: Vec::new() };
+        for (i, line) in lines.enumerate() {
+            for (j, c) in line.chars().enumerate() {
+                grid.cells.push(cell(c).ok_or(GridError::BadCell { line: i + 1, column: j + 1, c })?);
+            }
+            let width = grid.cells.len() - grid.width * grid.height;
+            if i == 0 {
+                grid.width = width;
+            } else if width != grid.width {
+                return Err(GridError::Ragged { line: i + 1, width, expected: grid.width });
+            }
+            grid.height += 1;
+        }
+        if grid.width == 0 {
+            return Err(GridError::Empty);
+        }
+        Ok(grid)
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Cell at `pos`, or `None` if it's outside the grid.
+    pub fn get(&self, pos: Pos) -> Option<&T> {
+        self.index_of(pos).map(|i| &self.cells[i])
+    }
+
+    /// Mutable cell at `pos`, or `None` if it's outside the grid.
+    pub fn get_mut(&mut self, pos: Pos) -> Option<&mut T> {
+        self.index_of(pos).map(|i| &mut self.cells[i])
+    }
+
+    fn index_of(&self, (x, y): Pos) -> Option<usize> {
+        (x < self.width && y < self.height).then_some(y * self.width + x)
+    }
+
+    /// Next position from `pos` in the direction `dir`, or `None` at the edge of the grid.
+    pub fn step(&self, (x, y): Pos, dir: Dir) -> Option<Pos> {
+        let (dx, dy) = dir.delta();
+        let pos = (x.checked_add_signed(dx)?, y.checked_add_signed(dy)?);
+        self.index_of(pos).map(|_| pos)
+    }
+
+    /// All the positions, row by row.
+    pub fn positions(&self) -> impl Iterator<Item = Pos> {
+        let width = self.width;
+        (0..self.cells.len()).map(move |i| (i % width, i / width))
+    }
+
+    /// All the cells with their position, row by row.
+    pub fn iter(&self) -> impl Iterator<Item = (Pos, &T)> {
+        self.positions().zip(self.cells.iter())
+    }
+
+    /// Cells of the row `y`, from left to right. Panics if the row is outside the grid.
+    pub fn row(&self, y: usize) -> impl DoubleEndedIterator<Item = &T> {
+        assert!(y < self.height, "row {y} outside the grid of height {}", self.height);
+        self.cells[y * self.width..(y + 1) * self.width].iter()
+    }
+
+    /// Cells of the column `x`, from top to bottom. Panics if the column is outside the grid.
+    pub fn column(&self, x: usize) -> impl DoubleEndedIterator<Item = &T> {
+        assert!(x < self.width, "column {x} outside the grid of width {}", self.width);
+        (0..self.height).map(move |y| &self.cells[y * self.width + x])
+    }
+
+    /// Rows of the grid, from top to bottom.
+    pub fn rows(&self) -> impl Iterator<Item = &[T]> {
+        self.cells.chunks(self.width.max(1))
+    }
+
+    /// Cells with their position from `pos` (excluded) to the edge of the grid in the direction `dir`.
+    pub fn ray(&self, pos: Pos, dir: Dir) -> impl Iterator<Item = (Pos, &T)> {
+        std::iter::successors(self.step(pos, dir), move |&p| self.step(p, dir)).map(|p| (p, &self[p]))
+    }
+
+    /// Cells adjacent to `pos` with their position, in the order of `Dir::ALL`.
+    pub fn neighbors(&self, pos: Pos) -> impl Iterator<Item = (Pos, &T)> {
+        Dir::ALL.into_iter().filter_map(move |dir| self.step(pos, dir)).map(|p| (p, &self[p]))
+    }
+
+    /// Grid of the same size, with each cell converted by `f`.
+    pub fn map<U, F>(&self, mut f: F) -> Grid<U> where F: FnMut(Pos, &T) -> U {
+        Grid { width: self.width, height: self.height, cells: self.iter().map(|(p, c)| f(p, c)).collect() }
+    }
+}
+
+impl<T> Index<Pos> for Grid<T> {
+    type Output = T;
+
+    /// Cell at `pos`. Panics if it's outside the grid.
+    fn index(&self, pos: Pos) -> &T {
+        match self.index_of(pos) {
+            Some(i) => &self.cells[i],
+            None => panic!("position {pos:?} outside the grid of {}x{}", self.width, self.height)
+        }
+    }
+}
+
+impl<T> IndexMut<Pos> for Grid<T> {
+    /// Cell at `pos`. Panics if it's outside the grid.
+    fn index_mut(&mut self, pos: Pos) -> &mut T {
+        match self.index_of(pos) {
+            Some(i) => &mut self.cells[i],
+            None => panic!("position {pos:?} outside the grid of {}x{}", self.width, self.height)
+        }
+    }
+}
+
+/// One line per row, with the cells side by side.
+impl<T: Display> Display for Grid<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for row in self.rows() {
+            for cell in row {
+                write!(f, "{cell}")?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grid() -> Grid<u32> {
+        let lines = "123\n456".lines().map(|s| s.to_string());
+        Grid::parse(lines, |c| c.to_digit(10)).unwrap()
+    }
+
+    #[test]
+    fn grid_access() {
+        let mut grid = grid();
+        assert_eq!((grid.width(), grid.height()), (3, 2));
+        assert_eq!(grid[(2, 0)], 3);
+        assert_eq!(grid.get((0, 1)), Some(&4));
+        assert_eq!(grid.get((3, 0)), None);
+        assert_eq!(grid.get((0, 2)), None);
+        *grid.get_mut((1, 1)).unwrap() = 0;
+        grid[(2, 1)] += 1;
+        assert_eq!(grid.to_string(), "123\n407\n");
+        assert_eq!(grid.map(|(x, y), c| c * 10 + (x + y) as u32).to_string(), "102132\n41273\n");
+    }
+
+    #[test]
+    #[should_panic(expected = "position (0, 2) outside the grid of 3x2")]
+    fn grid_out_of_bounds() {
+        let _ = grid()[(0, 2)];
+    }
+
+    #[test]
+    fn grid_iterators() {
+        let grid = grid();
+        assert_eq!(grid.row(1).collect::<Vec<_>>(), [&4, &5, &6]);
+        assert_eq!(grid.column(2).rev().collect::<Vec<_>>(), [&6, &3]);
+        assert_eq!(grid.rows().collect::<Vec<_>>(), [[1, 2, 3], [4, 5, 6]]);
+        assert_eq!(grid.positions().last(), Some((2, 1)));
+        assert_eq!(grid.iter().nth(4), Some(((1, 1), &5)));
+        assert_eq!(grid.ray((0, 1), Dir::Right).collect::<Vec<_>>(), [((1, 1), &5), ((2, 1), &6)]);
+        assert_eq!(grid.ray((0, 1), Dir::Left).count(), 0);
+        assert_eq!(grid.step((2, 0), Dir::Up), None);
+        assert_eq!(grid.neighbors((1, 0)).collect::<Vec<_>>(), [((1, 1), &5), ((0, 0), &1), ((2, 0), &3)]);
+    }
+
+    #[test]
+    fn grid_errors() {
+        let parse = |s: &str| Grid::parse(s.lines().map(|l| l.to_string()), |c| c.to_digit(10));
+        assert_eq!(parse(""), Err(GridError::Empty));
+        assert_eq!(parse("12\n345"), Err(GridError::Ragged { line: 2, width: 3, expected: 2 }));
+        assert_eq!(parse("12\n3x").unwrap_err().to_string(), "line 2, column 2: invalid cell 'x'");
+    }
+}
@@ -0,0 +1,111 @@
+use std::env;
+use std::fs;
+use std::process::ExitCode;
+use itertools::Itertools;
+use advent2022::day05::{Crane, Crate, CrateMover9000, CrateMover9001, Replay};
+use advent2022::day07::{parse_with_warnings, print_answers, DiskConfig, Query};
+
+const USAGE: &str = "\
+usage: advent2022 replay <file> [--crane 9000|9001] <position> [<stack>]
+       advent2022 replay <file> [--crane 9000|9001] diff <from> <to>
+       advent2022 query <transcript> <query>
+       advent2022 space <transcript> [--disk capacity=<bytes>,required=<bytes>,threshold=<bytes>]";
+
+fn main() -> ExitCode {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    let result = match args.split_first() {
+        Some((command, args)) if command == "replay" => replay(args),
+        Some((command, args)) if command == "query" => query(args),
+        Some((command, args)) if command == "space" => space(args),
+        _ => Err(USAGE.to_string())
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Lines of the file at `path`.
+fn read_lines(path: &str) -> Result<Vec<String>, String> {
+    fs::read_to_string(path)
+        .map(|text| text.lines().map(|s| s.to_string()).collect())
+        .map_err(|e| format!("{path}: {e}"))
+}
+
+/// Removes the option `name` and its value from `args`, and returns the value.
+fn take_option(args: &mut Vec<String>, name: &str) -> Result<Option<String>, String> {
+    let Some(index) = args.iter().position(|a| a == name) else { return Ok(None) };
+    if index + 1 == args.len() {
+        return Err(format!("missing value after {name}"));
+    }
+    let value = args.remove(index + 1);
+    args.remove(index);
+    Ok(Some(value))
+}
+
+fn number(arg: &str) -> Result<usize, String> {
+    arg.parse().map_err(|_| format!("'{arg}' is not a number"))
+}
+
+fn show_crates(crates: &[Crate]) -> String {
+    crates.iter().map(|c| format!("[{c}]")).join(" ")
+}
+
+/// Replays the day05 moves: shows the stacks, or one stack (numbered from 1), after `position`
+/// moves, or the stacks which differ between two positions.
+fn replay(args: &[String]) -> Result<(), String> {
+    let mut args = args.to_vec();
+    let crane: &dyn Crane = match take_option(&mut args, "--crane")?.as_deref() {
+        None | Some("9000") => &CrateMover9000,
+        Some("9001") => &CrateMover9001,
+        Some(crane) => return Err(format!("unknown crane '{crane}'"))
+    };
+    let Some((file, args)) = args.split_first() else { return Err(USAGE.to_string()) };
+    let mut replay = Replay::parse(read_lines(file)?.into_iter(), crane).map_err(|e| e.to_string())?;
+    match args {
+        [position] => println!("{}", replay.stacks_at(number(position)?)),
+        [position, stack] => {
+            let (position, stack) = (number(position)?, number(stack)?);
+            if stack == 0 || stack > replay.stacks().len() {
+                return Err(format!("stack {stack} doesn't exist, there are {} stacks", replay.stacks().len()));
+            }
+            println!("{}", show_crates(&replay.stack_at(position, stack - 1)));
+        }
+        [diff, from, to] if diff == "diff" => {
+            for d in replay.diff(number(from)?, number(to)?) {
+                println!("{}: {} -> {}", d.index + 1, show_crates(&d.before), show_crates(&d.after));
+            }
+        }
+        _ => return Err(USAGE.to_string())
+    }
+    Ok(())
+}
+
+/// Lists the size and path of the entries of the day07 tree which match the query, like
+/// `size > 100000 and type = dir`.
+fn query(args: &[String]) -> Result<(), String> {
+    let [transcript, query] = args else { return Err(USAGE.to_string()) };
+    let query = query.parse::<Query>().map_err(|e| format!("invalid query {e}"))?;
+    let (root, _) = parse_with_warnings(read_lines(transcript)?.into_iter());
+    for m in query.run(&root) {
+        println!("{:>10}  {}", m.entry.size(), m.path);
+    }
+    Ok(())
+}
+
+/// Prints the day07 answers for the tree built from a transcript, with the disk parameters
+/// given by `--disk` (the missing ones keep the puzzle's value).
+fn space(args: &[String]) -> Result<(), String> {
+    let mut args = args.to_vec();
+    let config = match take_option(&mut args, "--disk")? {
+        Some(disk) => disk.parse::<DiskConfig>().map_err(|e| e.to_string())?,
+        None => DiskConfig::default()
+    };
+    let [transcript] = &args[..] else { return Err(USAGE.to_string()) };
+    let (root, _) = parse_with_warnings(read_lines(transcript)?.into_iter());
+    print_answers(&root, &config);
+    Ok(())
+}